@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+/// Summary of the arrival times of a flow's datagrams.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ArrivalReport {
+    /// arrival of the first datagram in ns since the unix epoch
+    pub first_ns: u64,
+    /// arrival of the last datagram in ns since the unix epoch
+    pub last_ns: u64,
+    /// median spacing between consecutive datagrams in ns
+    pub median_gap_ns: u64,
+}
+
+impl ArrivalReport {
+    /// Rate derived from the spacing of the datagrams.
+    ///
+    /// If the flow exceeds the bottleneck, datagrams leave it back-to-back
+    /// and their spacing reflects the bottleneck capacity.
+    pub fn spacing_pps(&self) -> u32 {
+        1_000_000_000u64
+            .checked_div(self.median_gap_ns)
+            .unwrap_or(0) as u32
+    }
+}
+
+pub struct ArrivalTracker {
+    first: Option<Duration>,
+    last: Option<Duration>,
+    gaps: Vec<u64>,
+}
+
+impl ArrivalTracker {
+    pub fn new() -> ArrivalTracker {
+        ArrivalTracker {
+            first: None,
+            last: None,
+            gaps: vec![],
+        }
+    }
+
+    /// Account for an arrival and return the gap to the previous one.
+    pub fn track(&mut self, ts: Duration) -> Option<u64> {
+        let mut gap = None;
+        if let Some(last) = self.last {
+            if ts >= last {
+                gap = Some(as_ns(ts - last));
+                self.gaps.extend(gap);
+            }
+        } else {
+            self.first = Some(ts);
+        }
+        self.last = Some(ts);
+        gap
+    }
+
+    pub fn report(&self) -> ArrivalReport {
+        ArrivalReport {
+            first_ns: as_ns(self.first.unwrap_or_default()),
+            last_ns: as_ns(self.last.unwrap_or_default()),
+            median_gap_ns: median(&self.gaps),
+        }
+    }
+}
+
+pub fn median(gaps: &[u64]) -> u64 {
+    let mut gaps = gaps.to_vec();
+    gaps.sort_unstable();
+    gaps.get(gaps.len() / 2).cloned().unwrap_or(0)
+}
+
+pub fn as_ns(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Clocks drift apart by far less than this. A faster change of the
+/// smallest delays is put down to a queue that builds up or drains.
+const MAX_DRIFT: f64 = 100e-6;
+
+/// Timestamps of a datagram that went to the server and back, each in ns
+/// since the unix epoch by the clock of the respective host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exchange {
+    /// sent by the client
+    pub t1: u64,
+    /// received by the server
+    pub t2: u64,
+    /// sent back by the server
+    pub t3: u64,
+    /// received by the client
+    pub t4: u64,
+}
+
+impl Exchange {
+    /// Round trip time without the time spent at the server.
+    pub fn rtt(&self) -> i64 {
+        (self.t4 as i64 - self.t1 as i64) - (self.t3 as i64 - self.t2 as i64)
+    }
+
+    /// Offset of the server's clock to the client's, if both directions
+    /// took equally long.
+    pub fn offset(&self) -> i64 {
+        ((self.t2 as i64 - self.t1 as i64)
+            + (self.t3 as i64 - self.t4 as i64))
+            / 2
+    }
+
+    /// One-way delays to and from the server given the clock `offset`.
+    pub fn one_way(&self, offset: i64) -> (i64, i64) {
+        (
+            self.t2 as i64 - self.t1 as i64 - offset,
+            self.t4 as i64 - self.t3 as i64 + offset,
+        )
+    }
+}
+
+/// Offset of the server's clock to the client's.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ClockOffset {
+    pub offset_ns: i64,
+    /// the true offset is within this many ns of the estimate
+    pub uncertainty_ns: u64,
+}
+
+impl fmt::Display for ClockOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "clock offset {:+.3} ms +/- {:.3} ms",
+            self.offset_ns as f64 / 1e6,
+            self.uncertainty_ns as f64 / 1e6
+        )
+    }
+}
+
+/// Estimate the clock offset from the exchange with the smallest round
+/// trip time, which suffered least from queueing. However asymmetric the
+/// paths are, the error is at most half that round trip time.
+pub fn estimate(exchanges: &[Exchange]) -> Option<ClockOffset> {
+    let best = exchanges.iter().min_by_key(|e| e.rtt())?;
+    Some(ClockOffset {
+        offset_ns: best.offset(),
+        uncertainty_ns: best.rtt().max(0) as u64 / 2,
+    })
+}
+
+/// Drift of the receiver's clock against the sender's in ns per ns, from
+/// the `delays` of datagrams by their send time.
+///
+/// The smallest delay of each window of `window` ns is taken as the
+/// delay through empty queues, which changes with the drift only. The
+/// drift is the slope of the line that fits these minimums best.
+pub fn drift(delays: &[(u64, i64)], window: u64) -> f64 {
+    let first = match delays.iter().map(|d| d.0).min() {
+        Some(first) => first,
+        None => return 0.0,
+    };
+    let mut minimums: BTreeMap<u64, (u64, i64)> = BTreeMap::new();
+    for &(sent, delay) in delays {
+        let min = minimums
+            .entry((sent - first) / window)
+            .or_insert((sent, delay));
+        if delay < min.1 {
+            *min = (sent, delay);
+        }
+    }
+    if minimums.len() < 2 {
+        return 0.0;
+    }
+
+    let n = minimums.len() as f64;
+    let points: Vec<(f64, f64)> = minimums
+        .values()
+        .map(|&(sent, delay)| ((sent - first) as f64, delay as f64))
+        .collect();
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let cov: f64 =
+        points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let var: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let slope = if var > 0.0 { cov / var } else { 0.0 };
+    if slope.abs() > MAX_DRIFT {
+        0.0
+    } else {
+        slope
+    }
+}
@@ -0,0 +1,195 @@
+use analyze::arrival::as_ns;
+use analyze::clock::{self, ClockOffset, Exchange};
+use std::fmt;
+use std::time::Duration;
+
+/// Length of the windows whose smallest delays reveal the clock drift.
+const DRIFT_WINDOW: u64 = 1_000_000_000;
+
+/// One-way delays of the stamped datagrams of a flow.
+///
+/// Sender and receiver clocks are not synchronized, so the delays include
+/// their offset, unless it was estimated. Queueing delays relative to the
+/// smallest delay of the flow do not. Delays are corrected for the drift of
+/// the clocks during the flow.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct DelayReport {
+    /// smallest one-way delay in ns, including the clock offset
+    pub min_ns: i64,
+    /// median queueing delay in ns
+    pub p50_ns: u64,
+    pub p95_ns: u64,
+    pub p99_ns: u64,
+    /// drift of the receiver's clock against the sender's in ppm
+    #[serde(default)]
+    pub drift_ppm: f64,
+    /// offset of the receiver's clock to the sender's, if estimated
+    #[serde(default)]
+    pub clock: Option<ClockOffset>,
+}
+
+impl DelayReport {
+    /// Smallest one-way delay without the clock offset and the
+    /// uncertainty of the offset, if estimated.
+    pub fn one_way(&self) -> Option<(i64, u64)> {
+        self.clock
+            .map(|c| (self.min_ns - c.offset_ns, c.uncertainty_ns))
+    }
+}
+
+impl fmt::Display for DelayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |ns: u64| ns as f64 / 1e6;
+        write!(
+            f,
+            "queueing delay p50 {:.2} ms, p95 {:.2} ms, p99 {:.2} ms",
+            ms(self.p50_ns),
+            ms(self.p95_ns),
+            ms(self.p99_ns)
+        )?;
+        if let Some((min, uncertainty)) = self.one_way() {
+            write!(
+                f,
+                ", one-way delay {:.2} ms +/- {:.2} ms",
+                min as f64 / 1e6,
+                ms(uncertainty)
+            )?;
+        }
+        if self.drift_ppm != 0.0 {
+            write!(f, ", clock drift {:+.1} ppm", self.drift_ppm)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct DelayTracker {
+    /// send times and delays
+    delays: Vec<(u64, i64)>,
+}
+
+impl DelayTracker {
+    pub fn new() -> DelayTracker {
+        DelayTracker { delays: vec![] }
+    }
+
+    /// Account for a datagram sent at `sent_ns` by the sender's clock that
+    /// arrived at `ts`.
+    pub fn track(&mut self, sent_ns: u64, ts: Duration) {
+        self.delays
+            .push((sent_ns, as_ns(ts) as i64 - sent_ns as i64));
+    }
+
+    /// Summary of the delays, if any datagram was stamped.
+    pub fn report(&self) -> Option<DelayReport> {
+        let first = self.delays.iter().map(|d| d.0).min()?;
+        let drift = clock::drift(&self.delays, DRIFT_WINDOW);
+        let delays: Vec<i64> = self
+            .delays
+            .iter()
+            .map(|&(sent, delay)| {
+                delay - (drift * (sent - first) as f64).round() as i64
+            })
+            .collect();
+        let min = *delays.iter().min()?;
+        let mut queued: Vec<u64> =
+            delays.iter().map(|&d| (d - min) as u64).collect();
+        queued.sort_unstable();
+        Some(DelayReport {
+            min_ns: min,
+            p50_ns: percentile(&queued, 50),
+            p95_ns: percentile(&queued, 95),
+            p99_ns: percentile(&queued, 99),
+            drift_ppm: drift * 1e6,
+            clock: None,
+        })
+    }
+}
+
+/// The `p`th percentile of the sorted `values`.
+pub fn percentile(values: &[u64], p: usize) -> u64 {
+    if values.is_empty() {
+        return 0;
+    }
+    values[(values.len() - 1) * p / 100]
+}
+
+/// Percentiles of a set of delays.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Percentiles {
+    pub min_ns: u64,
+    pub p50_ns: u64,
+    pub p90_ns: u64,
+    pub p99_ns: u64,
+}
+
+impl Percentiles {
+    pub fn new(mut values: Vec<u64>) -> Percentiles {
+        values.sort_unstable();
+        Percentiles {
+            min_ns: values.first().cloned().unwrap_or(0),
+            p50_ns: percentile(&values, 50),
+            p90_ns: percentile(&values, 90),
+            p99_ns: percentile(&values, 99),
+        }
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |ns: u64| ns as f64 / 1e6;
+        write!(
+            f,
+            "min {:.2} ms, p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms",
+            ms(self.min_ns),
+            ms(self.p50_ns),
+            ms(self.p90_ns),
+            ms(self.p99_ns)
+        )
+    }
+}
+
+/// Round trip and one-way delays of probes echoed by the server.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RttReport {
+    pub sent: u32,
+    pub received: u32,
+    /// round trip times without the time spent at the server
+    pub rtt: Percentiles,
+    /// delays to the server, given the clock offset
+    pub up: Percentiles,
+    /// delays from the server, given the clock offset
+    pub down: Percentiles,
+    pub offset: Option<ClockOffset>,
+}
+
+impl RttReport {
+    /// Summarize the `exchanges` of `sent` probes.
+    pub fn new(sent: u32, exchanges: &[Exchange]) -> RttReport {
+        let ns = |d: i64| d.max(0) as u64;
+        let offset = clock::estimate(exchanges);
+        let one_way: Vec<(i64, i64)> = exchanges
+            .iter()
+            .map(|e| e.one_way(offset.map_or(0, |o| o.offset_ns)))
+            .collect();
+        RttReport {
+            sent,
+            received: exchanges.len() as u32,
+            rtt: Percentiles::new(
+                exchanges.iter().map(|e| ns(e.rtt())).collect(),
+            ),
+            up: Percentiles::new(one_way.iter().map(|d| ns(d.0)).collect()),
+            down: Percentiles::new(one_way.iter().map(|d| ns(d.1)).collect()),
+            offset,
+        }
+    }
+}
+
+impl fmt::Display for RttReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rtt {}, {} of {} probes answered",
+            self.rtt, self.received, self.sent
+        )
+    }
+}
@@ -0,0 +1,26 @@
+/// Difference of the overheads that is put down to measurement noise.
+const TUNNEL_MARGIN: i64 = 8;
+
+/// How the per packet overheads of both address families relate.
+#[derive(Debug, PartialEq)]
+pub enum FamilyPath {
+    /// the overheads are the same
+    Native,
+    /// IPv6 carries extra bytes, e.g. 6in4 or 6rd
+    Ipv6Tunneled(i64),
+    /// IPv4 carries extra bytes, e.g. DS-Lite
+    Ipv4Tunneled(i64),
+}
+
+/// Compare the per packet overheads of the same path over IPv4 and IPv6,
+/// both on top of the IP packet.
+pub fn compare(v4_overhead: i64, v6_overhead: i64) -> FamilyPath {
+    let extra = v6_overhead - v4_overhead;
+    if extra > TUNNEL_MARGIN {
+        FamilyPath::Ipv6Tunneled(extra)
+    } else if extra < -TUNNEL_MARGIN {
+        FamilyPath::Ipv4Tunneled(-extra)
+    } else {
+        FamilyPath::Native
+    }
+}
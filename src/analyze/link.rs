@@ -0,0 +1,79 @@
+/// Spread of measured overheads around the true value, in bytes.
+const OVERHEAD_SIGMA: f64 = 4.0;
+/// Weight of a link type whose MTU differs from the measured one; MSS
+/// clamping or a lowered MTU on the router can hide the link's MTU.
+const MTU_MISMATCH: f64 = 0.1;
+/// Link types less likely than this are not reported.
+pub const MIN_CONFIDENCE: f64 = 0.05;
+
+/// An access link and the framing it adds to IP packets.
+#[derive(Debug, PartialEq)]
+pub struct LinkType {
+    pub name: &'static str,
+    /// per packet overhead on top of the IP packet
+    pub overhead: i64,
+    pub mtu: usize,
+}
+
+const fn link(name: &'static str, overhead: i64, mtu: usize) -> LinkType {
+    LinkType {
+        name,
+        overhead,
+        mtu,
+    }
+}
+
+/// Overheads as cake counts them. ATM links are listed with their
+/// encapsulation only, as cell padding is not detected.
+static LINK_TYPES: [LinkType; 17] = [
+    link("Ethernet", 38, 1500),
+    link("Ethernet with VLAN", 42, 1500),
+    link("DOCSIS", 18, 1500),
+    link("DS-Lite over DOCSIS", 58, 1460),
+    link("GPON with VLAN", 27, 1500),
+    link("PPPoE over GPON with VLAN", 35, 1492),
+    link("bridged VDSL2", 22, 1500),
+    link("PPPoE over VDSL2", 30, 1492),
+    link("PPPoE over VDSL2 with VLAN", 34, 1492),
+    link("PPPoE over ADSL, LLC/SNAP", 40, 1492),
+    link("PPPoE over ADSL, VC-mux", 32, 1492),
+    link("PPPoA over ADSL, LLC", 14, 1500),
+    link("PPPoA over ADSL, VC-mux", 10, 1500),
+    link("bridged ADSL, LLC/SNAP", 32, 1500),
+    link("bridged ADSL, VC-mux", 24, 1500),
+    link("routed ADSL, LLC/SNAP", 16, 1500),
+    link("routed ADSL, VC-mux", 8, 1500),
+];
+
+/// Likely link types for a per packet overhead on top of the IP packet
+/// and the path MTU, if known, along with their confidence.
+///
+/// Confidences add up to 1 over all link types; the most likely come
+/// first.
+pub fn fingerprint(
+    overhead: i64,
+    mtu: Option<usize>,
+) -> Vec<(&'static LinkType, f64)> {
+    let weights: Vec<f64> = LINK_TYPES
+        .iter()
+        .map(|link| {
+            let dist = (overhead - link.overhead) as f64 / OVERHEAD_SIGMA;
+            let fit = (-0.5 * dist * dist).exp();
+            match mtu {
+                Some(mtu) if mtu != link.mtu => fit * MTU_MISMATCH,
+                _ => fit,
+            }
+        })
+        .collect();
+    let total: f64 = weights.iter().sum();
+    if total == 0.0 {
+        return vec![];
+    }
+    let mut links: Vec<(&'static LinkType, f64)> = LINK_TYPES
+        .iter()
+        .zip(weights)
+        .map(|(link, weight)| (link, weight / total))
+        .collect();
+    links.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    links
+}
@@ -0,0 +1,39 @@
+use tos::{Dscp, Ecn};
+
+/// Datagrams that arrived with the same TOS byte.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TosReport {
+    pub tos: u8,
+    pub cnt: u32,
+}
+
+/// Code points that arrived instead of `sent`, along with their counts.
+///
+/// An empty result means the marking was honored, or that the receiver
+/// could not read it.
+pub fn remarked(sent: Dscp, received: &[TosReport]) -> Vec<(Dscp, u32)> {
+    let mut remarked: Vec<(Dscp, u32)> = vec![];
+    for report in received {
+        let dscp = Dscp::from_tos(report.tos);
+        if dscp == sent {
+            continue;
+        }
+        match remarked.iter_mut().find(|r| r.0 == dscp) {
+            Some(r) => r.1 += report.cnt,
+            None => remarked.push((dscp, report.cnt)),
+        }
+    }
+    remarked
+}
+
+/// Datagrams that were sent ECN capable but arrived without ECN marking.
+pub fn ecn_cleared(sent: Ecn, received: &[TosReport]) -> u32 {
+    if sent == Ecn::NotEct {
+        return 0;
+    }
+    received
+        .iter()
+        .filter(|r| Ecn::from_tos(r.tos) == Ecn::NotEct)
+        .map(|r| r.cnt)
+        .sum()
+}
@@ -1,129 +1,1025 @@
 extern crate serde_json;
 
+pub mod arrival;
+pub mod clock;
+pub mod delay;
+pub mod family;
+pub mod link;
+pub mod marking;
+pub mod mtu;
+pub mod parallel;
 pub mod sequence;
+pub mod series;
+pub mod size;
+pub mod tracker;
+pub mod train;
+pub mod transport;
 
-use analyze::sequence::Sequencer;
+use analyze::arrival::as_ns;
+use analyze::clock::{ClockOffset, Exchange};
+use analyze::delay::RttReport;
+use analyze::parallel::Flows;
+use analyze::sequence::{SequenceReport, Sequencer};
+use analyze::series::SERIES_INTERVAL;
+use analyze::tracker::FlowTracker;
+use bitrate::Framing;
 use control::{ControlMessage, ControlStream};
-use flow::Flow;
-use std::net::{TcpStream, UdpSocket};
-use std::time::Duration;
+use echo;
+use flow::{Flow, FlowStats};
+use nat::{self, Keepalive, NatType};
+use pacing::Pacing;
+use pmtu;
+use profile::RateProfile;
+use receiver::{since_epoch, Receiver};
+use sizes::PacketSizes;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream, UdpSocket};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+use tcp::{self, TcpReport, TcpStats};
+use tos::{self, Dscp, Ecn};
+
+/// Interval of keepalives on idle flow sockets, well below the UDP
+/// timeouts of common NATs.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+/// Time requests over the control connection to estimate the clock
+/// offset from.
+const CLOCK_EXCHANGES: usize = 8;
+/// Time to wait for datagrams the client's NAT might let through
+/// unsolicited.
+const FILTER_WAIT: Duration = Duration::from_millis(500);
+/// Datagrams of downstream flows read at once
+const RECV_BATCH: usize = 32;
+/// Time without datagrams after which a downstream flow is over
+const DOWNSTREAM_LINGER: Duration = Duration::from_millis(1000);
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SequencedPayload {
+    pub epoch: u32,
     pub seq: u32,
+    /// packet train the datagram belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub train: Option<u32>,
+    /// flow id on a shared server port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flow: Option<u16>,
+    /// send time in ns since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_ns: Option<u64>,
 }
 
 impl SequencedPayload {
-    pub fn flatten_into(self, buf: &mut [u8]) {
+    /// Write the payload into `buf`, padded with spaces. Fails if the
+    /// header does not fit, as the receiver could not parse it.
+    pub fn flatten_into(self, buf: &mut [u8]) -> Result<(), &'static str> {
         let mut payload_vec = serde_json::to_vec(&self).unwrap();
+        if payload_vec.len() > buf.len() {
+            return Err("payload header exceeds the datagram");
+        }
 
         payload_vec.resize(buf.len(), ' ' as u8);
         buf.copy_from_slice(&payload_vec);
+        Ok(())
     }
 }
 
 use std::net::SocketAddr;
-pub fn find_max_pps(
-    sock_addr: SocketAddr,
-    pktlen: usize,
-) -> Result<u32, String> {
-    let mut pps = 1000;
-    let secs = 3;
-    let mut highest_pps: Option<u32> = None;
-    let mut no_update_iters = 0;
 
-    let mut ctrl_sk =
-        TcpStream::connect(sock_addr).expect("open control connection");
+/// Sender statistics of a flow along with the receiver's report.
+pub type FlowResult = (FlowStats, SequenceReport);
 
-    loop {
-        ctrl_sk.send_msg(ControlMessage::RequestFlow)?;
-        let udp_port;
-        loop {
-            match ctrl_sk.recv_msg().expect("initiate new flow") {
-                ControlMessage::ExpectFlow(p) => {
-                    udp_port = p;
-                    break;
+/// Direction of a flow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// from the client to the server
+    Up,
+    /// from the server to the client
+    Down,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Direction::Up => write!(f, "upstream"),
+            Direction::Down => write!(f, "downstream"),
+        }
+    }
+}
+
+/// Payload generator of the session's flows.
+type FillPacket =
+    Box<dyn FnMut(Box<[u8]>) -> Result<Box<[u8]>, &'static str> + Send>;
+
+/// A measurement session with a server.
+///
+/// The control connection and the UDP socket are kept open across flows.
+/// Consecutive flows are told apart by the epoch id in their payload.
+pub struct Session {
+    ctrl_sk: TcpStream,
+    server: IpAddr,
+    sk: Option<UdpSocket>,
+    /// server port of the flow, or its id on a shared port
+    port: u16,
+    /// whether the server serves all flows on a single port
+    shared: bool,
+    /// sockets and server ports of further flows that run in parallel
+    parallel: Vec<(UdpSocket, u16)>,
+    epoch: u32,
+    batch: usize,
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    gso: bool,
+    pacing: Pacing,
+    tos: u8,
+    framing: Framing,
+    /// whether datagrams carry their send time
+    timestamps: bool,
+    /// offset of the server's clock to the client's, once estimated
+    clock: Option<ClockOffset>,
+    /// interval of the receiver's throughput series
+    series_interval: Duration,
+    /// address the server sees the session socket at, if it answered the
+    /// hole punching
+    mapped: Option<SocketAddr>,
+    keepalive: Keepalive,
+}
+
+impl Session {
+    pub fn connect(sock_addr: SocketAddr) -> Result<Session, String> {
+        let mut ctrl_sk =
+            TcpStream::connect(sock_addr).map_err(|e| e.to_string())?;
+        let (sk, flow, mapped) = request_flow(&mut ctrl_sk, sock_addr.ip())?;
+        let keepalive = Keepalive::start(KEEPALIVE);
+        keepalive.add(&sk).map_err(|e| e.to_string())?;
+
+        Ok(Session {
+            ctrl_sk,
+            server: sock_addr.ip(),
+            sk: Some(sk),
+            port: flow.key(),
+            shared: flow.id.is_some(),
+            parallel: vec![],
+            epoch: 0,
+            batch: 1,
+            #[cfg(all(target_os = "linux", feature = "mmsg"))]
+            gso: false,
+            pacing: Pacing::Sleep,
+            tos: 0,
+            framing: Framing::new(sock_addr.is_ipv6()),
+            timestamps: false,
+            clock: None,
+            series_interval: SERIES_INTERVAL,
+            mapped,
+            keepalive,
+        })
+    }
+
+    /// Local address of the session socket and the address the server
+    /// sees it at, if known.
+    pub fn addresses(
+        &self,
+    ) -> Result<(SocketAddr, Option<SocketAddr>), String> {
+        let sk = self.sk.as_ref().ok_or("session socket is gone")?;
+        let local = sk.local_addr().map_err(|e| e.to_string())?;
+        Ok((local, self.mapped))
+    }
+
+    /// Tell how a NAT between client and server maps and filters UDP.
+    ///
+    /// A spare socket punches holes to two new flows of the server, which
+    /// then sends to it from a third port. If the server serves all flows
+    /// on a single port, a symmetric NAT passes for a cone NAT.
+    pub fn detect_nat(&mut self) -> Result<NatType, String> {
+        let sk = UdpSocket::bind((unspecified(self.server), 0))
+            .map_err(|e| e.to_string())?;
+        let mut keys = vec![];
+        let mut mapped = vec![];
+        for _ in 0..2 {
+            let flow = request_port(&mut self.ctrl_sk)?;
+            keys.push(flow.key());
+            let to = SocketAddr::new(self.server, flow.port);
+            mapped.push(nat::punch(&sk, Some(to), flow.key())?);
+        }
+
+        self.ctrl_sk
+            .send_msg(ControlMessage::RequestProbe(mapped[0]))?;
+        match self.ctrl_sk.recv_msg()? {
+            ControlMessage::ProbeSent => (),
+            _ => return Err("unknown control message received".to_string()),
+        }
+        let unsolicited = nat::await_datagram(&sk, FILTER_WAIT);
+
+        for key in keys {
+            self.terminate_flow(key)?;
+        }
+        // the spare socket takes the same route as the control connection
+        let local_ip =
+            self.ctrl_sk.local_addr().map_err(|e| e.to_string())?;
+        let local_port = sk.local_addr().map_err(|e| e.to_string())?.port();
+        let local = nat::unmap(SocketAddr::new(local_ip.ip(), local_port));
+        Ok(nat::classify(local, &mapped, unsolicited))
+    }
+
+    pub fn set_batch(&mut self, batch: usize) {
+        self.batch = batch;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    pub fn set_gso(&mut self, gso: bool) {
+        self.gso = gso;
+    }
+
+    pub fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacing = pacing;
+    }
+
+    /// Mark the datagrams of further flows with the TOS byte `tos`.
+    pub fn set_tos(&mut self, tos: u8) {
+        self.tos = tos;
+    }
+
+    pub fn tos(&self) -> u8 {
+        self.tos
+    }
+
+    /// Account for `overhead` bytes per packet on top of the IP packet in
+    /// bit rates.
+    pub fn set_overhead(&mut self, overhead: usize) {
+        self.framing = self.framing.with_overhead(overhead);
+    }
+
+    /// Stamp the datagrams of further flows with their send time, so that
+    /// the receiver reports their delays.
+    pub fn set_timestamps(&mut self, timestamps: bool) {
+        self.timestamps = timestamps;
+    }
+
+    /// Estimate the offset of the server's clock to the client's from
+    /// time requests over the control connection. Delay reports of
+    /// further flows then carry it, so that they tell one-way delays.
+    pub fn sync_clock(&mut self) -> Result<ClockOffset, String> {
+        let mut exchanges = vec![];
+        for _ in 0..CLOCK_EXCHANGES {
+            self.ctrl_sk.send_msg(ControlMessage::TimeRequest(as_ns(
+                since_epoch(),
+            )))?;
+            match self.ctrl_sk.recv_msg()? {
+                ControlMessage::TimeReply(t1, t2, t3) => {
+                    exchanges.push(Exchange {
+                        t1,
+                        t2,
+                        t3,
+                        t4: as_ns(since_epoch()),
+                    })
                 }
+                _ => {
+                    return Err("unknown control message received".to_string())
+                }
+            }
+        }
+        self.clock = clock::estimate(&exchanges);
+        self.clock.ok_or_else(|| "no clock offset".to_string())
+    }
+
+    /// Have the receiver report the throughput of further flows per
+    /// `interval`.
+    pub fn set_series_interval(&mut self, interval: Duration) {
+        self.series_interval = interval;
+    }
+
+    /// How the session's datagrams are framed on the way to the server.
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Whether the server serves all flows on one shared UDP port, so
+    /// that it refuses flows that need a port of their own.
+    pub fn shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Send a flow and return the sender's statistics along with the
+    /// receiver's report.
+    pub fn run_flow(
+        &mut self,
+        pps: u32,
+        pktlen: usize,
+        duration: Duration,
+    ) -> Result<FlowResult, String> {
+        self.run_profile(
+            RateProfile::Constant(pps),
+            PacketSizes::Fixed(pktlen),
+            duration,
+        )
+    }
+
+    /// Like `run_flow`, but with a rate that follows `profile` and
+    /// datagram sizes drawn from `sizes`.
+    pub fn run_profile(
+        &mut self,
+        profile: RateProfile,
+        sizes: PacketSizes,
+        duration: Duration,
+    ) -> Result<FlowResult, String> {
+        let epoch = self.epoch;
+        self.epoch += 1;
+
+        let sk = self.sk.take().ok_or("session socket is gone")?;
+        let port = self.port;
+        let mut flow = self.flow(sk, port, epoch, profile, sizes, duration);
+        let stats = flow.start_xmit();
+        self.sk = Some(flow.to_socket());
+
+        Ok((stats, self.terminate_epoch(port, epoch)?))
+    }
+
+    /// Run a flow in `direction`, see `run_flow` and `run_downstream`.
+    pub fn run_directed(
+        &mut self,
+        direction: Direction,
+        pps: u32,
+        pktlen: usize,
+        duration: Duration,
+    ) -> Result<FlowResult, String> {
+        match direction {
+            Direction::Up => self.run_flow(pps, pktlen, duration),
+            Direction::Down => self.run_downstream(pps, pktlen, duration),
+        }
+    }
+
+    /// Have the server send a flow to the client and return the server's
+    /// statistics along with the client's report.
+    pub fn run_downstream(
+        &mut self,
+        pps: u32,
+        pktlen: usize,
+        duration: Duration,
+    ) -> Result<FlowResult, String> {
+        let downstream = self.start_downstream(pps, pktlen, duration)?;
+        let port = downstream.port;
+        let r = downstream.receive().map(|r| self.with_server_clock(r));
+        let stats = self.finish_downstream(port)?;
+        Ok((stats, r?))
+    }
+
+    /// Send a flow to the server while it sends one back and return the
+    /// results of the upstream and the downstream flow.
+    pub fn run_bidirectional(
+        &mut self,
+        up_pps: u32,
+        down_pps: u32,
+        pktlen: usize,
+        duration: Duration,
+    ) -> Result<(FlowResult, FlowResult), String> {
+        let downstream = self.start_downstream(down_pps, pktlen, duration)?;
+        let port = downstream.port;
+        let receiver = thread::spawn(move || downstream.receive());
+        let up = self.run_flow(up_pps, pktlen, duration);
+        let r = receiver
+            .join()
+            .map_err(|_| "downstream receiver panicked")?
+            .map(|r| self.with_server_clock(r));
+        let stats = self.finish_downstream(port)?;
+        Ok((up?, (stats, r?)))
+    }
+
+    /// Have the server send a flow to a new socket, once that punched a
+    /// hole to it. The flow marks its datagrams with the session's TOS
+    /// byte and stamps them with their send time.
+    pub fn start_downstream(
+        &mut self,
+        pps: u32,
+        pktlen: usize,
+        duration: Duration,
+    ) -> Result<Downstream, String> {
+        self.ctrl_sk.send_msg(ControlMessage::RequestDownstream(
+            pps, pktlen, duration, self.tos,
+        ))?;
+        let port = loop {
+            match self.ctrl_sk.recv_msg()? {
+                ControlMessage::ExpectDownstream(p) => break p,
+                ControlMessage::Refused(reason) => return Err(reason),
+                _ => (),
+            }
+        };
+        let sk = UdpSocket::bind((unspecified(self.server), 0))
+            .map_err(|e| e.to_string())?;
+        sk.connect((self.server, port)).map_err(|e| e.to_string())?;
+        nat::punch(&sk, None, port)?;
+        Ok(Downstream {
+            sk,
+            port,
+            duration,
+            interval: self.series_interval,
+        })
+    }
+
+    /// Wait for the server to finish the downstream flow from `port` and
+    /// return its statistics.
+    fn finish_downstream(&mut self, port: u16) -> Result<FlowStats, String> {
+        self.ctrl_sk
+            .send_msg(ControlMessage::TerminateDownstream(port))?;
+        match self.ctrl_sk.recv_msg()? {
+            ControlMessage::DownstreamSent(stats) => Ok(stats),
+            _ => Err("unknown control message received".to_string()),
+        }
+    }
+
+    /// Measure round trip times to the server for `duration`.
+    pub fn measure_rtt(
+        &mut self,
+        duration: Duration,
+    ) -> Result<RttReport, String> {
+        self.with_rtt(duration, |_| Ok(())).map(|(_, rtt)| rtt)
+    }
+
+    /// Like `run_flow`, while measuring round trip times to the server.
+    pub fn run_flow_with_rtt(
+        &mut self,
+        pps: u32,
+        pktlen: usize,
+        duration: Duration,
+    ) -> Result<(FlowResult, RttReport), String> {
+        self.with_rtt(duration, |session| {
+            session.run_flow(pps, pktlen, duration)
+        })
+    }
+
+    /// Probe round trip times for `duration` over an echo port of the
+    /// server while `load` runs.
+    fn with_rtt<T, F>(
+        &mut self,
+        duration: Duration,
+        load: F,
+    ) -> Result<(T, RttReport), String>
+    where
+        F: FnOnce(&mut Session) -> Result<T, String>,
+    {
+        self.ctrl_sk.send_msg(ControlMessage::RequestEcho)?;
+        let port = loop {
+            match self.ctrl_sk.recv_msg()? {
+                ControlMessage::ExpectEcho(p) => break p,
+                ControlMessage::Refused(reason) => return Err(reason),
                 _ => (),
+            }
+        };
+        let sk = UdpSocket::bind((unspecified(self.server), 0))
+            .map_err(|e| e.to_string())?;
+        sk.connect((self.server, port)).map_err(|e| e.to_string())?;
+        tos::set_tos(&sk, self.tos).map_err(|e| e.to_string())?;
+        let prober = thread::spawn(move || {
+            echo::probe_rtt(&sk, echo::PROBE_INTERVAL, duration)
+        });
+
+        let loaded = load(self);
+        let rtt = prober
+            .join()
+            .map_err(|_| "round trip prober panicked")?
+            .map_err(|e| e.to_string());
+        self.ctrl_sk.send_msg(ControlMessage::TerminateEcho(port))?;
+        match self.ctrl_sk.recv_msg()? {
+            ControlMessage::EchoDone(_) => Ok((loaded?, rtt?)),
+            _ => Err("unknown control message received".to_string()),
+        }
+    }
+
+    /// Send `flows` at once, each from its own source port and marked
+    /// with its class, and return the results per flow.
+    ///
+    /// Every flow follows `profile` on its own.
+    pub fn run_parallel(
+        &mut self,
+        flows: &Flows,
+        profile: RateProfile,
+        sizes: PacketSizes,
+        duration: Duration,
+    ) -> Result<Vec<FlowResult>, String> {
+        while self.parallel.len() + 1 < flows.len() {
+            let (sk, flow, _) = request_flow(&mut self.ctrl_sk, self.server)?;
+            self.keepalive.add(&sk).map_err(|e| e.to_string())?;
+            self.parallel.push((sk, flow.key()));
+        }
+        let epoch = self.epoch;
+        self.epoch += 1;
+
+        let sk = self.sk.take().ok_or("session socket is gone")?;
+        let mut socks = vec![(sk, self.port)];
+        socks.extend(self.parallel.drain(..flows.len().max(1) - 1));
+        let senders: Vec<_> = socks
+            .into_iter()
+            .zip(&flows.0)
+            .map(|((sk, port), class)| {
+                let tos = match class {
+                    Some(dscp) => dscp.tos() | self.tos & 3,
+                    None => self.tos,
+                };
+                let mut flow = self
+                    .flow(
+                        sk,
+                        port,
+                        epoch,
+                        profile.clone(),
+                        sizes.clone(),
+                        duration,
+                    )
+                    .with_tos(tos);
+                let sender = thread::spawn(move || {
+                    let stats = flow.start_xmit();
+                    (stats, flow.to_socket())
+                });
+                (sender, port)
+            })
+            .collect();
+
+        let mut sent = vec![];
+        for (sender, port) in senders {
+            let (stats, sk) =
+                sender.join().map_err(|_| "flow sender panicked")?;
+            if port == self.port {
+                self.sk = Some(sk);
+            } else {
+                self.parallel.push((sk, port));
+            }
+            sent.push((stats, port));
+        }
+
+        let mut results = vec![];
+        for (stats, port) in sent {
+            results.push((stats, self.terminate_epoch(port, epoch)?));
+        }
+        Ok(results)
+    }
+
+    /// Set up a flow to the server's `port` with the session's settings.
+    fn flow(
+        &self,
+        sk: UdpSocket,
+        port: u16,
+        epoch: u32,
+        profile: RateProfile,
+        sizes: PacketSizes,
+        duration: Duration,
+    ) -> Flow<FillPacket> {
+        let mut seq = Sequencer::new();
+        let flow_id = self.flow_id(port);
+        let timestamps = self.timestamps;
+        // XXX this whole concept doesn't look very efficient
+        let fill_packet: FillPacket = Box::new(move |mut buf: Box<[u8]>| {
+            let payload = SequencedPayload {
+                epoch,
+                seq: seq.next_seq(),
+                train: None,
+                flow: flow_id,
+                sent_ns: if timestamps {
+                    Some(as_ns(since_epoch()))
+                } else {
+                    None
+                },
             };
+            payload.flatten_into(&mut buf)?;
+            Ok(buf)
+        });
+        self.with_gso(
+            Flow::from_socket(
+                profile.peak_pps(),
+                sizes.max_len(),
+                duration,
+                fill_packet,
+                sk,
+            )
+            .with_profile(profile)
+            .with_sizes(sizes)
+            .with_batch(self.batch)
+            .with_pacing(self.pacing)
+            .with_tos(self.tos)
+            .with_late_fill(timestamps),
+        )
+    }
+
+    /// Have `flow` send UDP GSO datagrams if the session does.
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    fn with_gso(&self, flow: Flow<FillPacket>) -> Flow<FillPacket> {
+        flow.with_gso(self.gso)
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "mmsg")))]
+    fn with_gso(&self, flow: Flow<FillPacket>) -> Flow<FillPacket> {
+        flow
+    }
+
+    /// Send trains of back-to-back datagrams and return the receiver's
+    /// report.
+    ///
+    /// Each train is given by the size and the number of its datagrams.
+    /// Trains are `spacing` apart.
+    pub fn run_trains(
+        &mut self,
+        trains: &[(usize, u32)],
+        spacing: Duration,
+    ) -> Result<SequenceReport, String> {
+        let epoch = self.epoch;
+        self.epoch += 1;
+
+        let mut seq = Sequencer::new();
+        let flow_id = self.flow_id(self.port);
+        {
+            let sk = self.sk.as_ref().ok_or("session socket is gone")?;
+            tos::set_tos(sk, self.tos).map_err(|e| e.to_string())?;
+            for (id, &(len, cnt)) in trains.iter().enumerate() {
+                let bufs = (0..cnt)
+                    .map(|_| {
+                        let mut buf = vec![0; len].into_boxed_slice();
+                        let payload = SequencedPayload {
+                            epoch,
+                            seq: seq.next_seq(),
+                            train: Some(id as u32),
+                            flow: flow_id,
+                            sent_ns: None,
+                        };
+                        payload.flatten_into(&mut buf)?;
+                        Ok(buf)
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                for buf in bufs.iter() {
+                    sk.send(buf).map_err(|e| e.to_string())?;
+                }
+                sleep(spacing);
+            }
         }
 
-        let sender = UdpSocket::bind(("::", 0)).expect("bind sender");
-        sender
-            .connect((sock_addr.ip(), udp_port))
-            .expect("connect to server");
+        let port = self.port;
+        self.terminate_epoch(port, epoch)
+    }
+
+    /// Send `cnt` datagrams of each of the lengths `lens` with the DF bit
+    /// set and return the receiver's report.
+    ///
+    /// Lengths the local interface does not take are left out.
+    pub fn probe_lens(
+        &mut self,
+        lens: &[usize],
+        cnt: u32,
+    ) -> Result<SequenceReport, String> {
+        let epoch = self.epoch;
+        self.epoch += 1;
 
         let mut seq = Sequencer::new();
-        let mut flow = Flow::from_socket(
+        let flow_id = self.flow_id(self.port);
+        {
+            let sk = self.sk.as_ref().ok_or("session socket is gone")?;
+            pmtu::set_dont_fragment(sk, true).map_err(|e| e.to_string())?;
+            let mut sent = Ok(());
+            'lens: for &len in lens {
+                for _ in 0..cnt {
+                    let mut buf = vec![0; len];
+                    let payload = SequencedPayload {
+                        epoch,
+                        seq: seq.next_seq(),
+                        train: None,
+                        flow: flow_id,
+                        sent_ns: None,
+                    };
+                    if let Err(e) = payload.flatten_into(&mut buf) {
+                        sent = Err(e.to_string());
+                        break 'lens;
+                    }
+                    match sk.send(&buf) {
+                        Err(ref e) if pmtu::is_too_big(e) => continue 'lens,
+                        Err(e) => {
+                            sent = Err(e.to_string());
+                            break 'lens;
+                        }
+                        Ok(_) => (),
+                    }
+                }
+                sleep(Duration::from_millis(1));
+            }
+            pmtu::set_dont_fragment(sk, false).map_err(|e| e.to_string())?;
+            sent?;
+        }
+
+        let port = self.port;
+        self.terminate_epoch(port, epoch)
+    }
+
+    /// Id the payload carries for the flow to `port`, if the server needs
+    /// it.
+    fn flow_id(&self, port: u16) -> Option<u16> {
+        if self.shared {
+            Some(port)
+        } else {
+            None
+        }
+    }
+
+    /// Run a bulk TCP transfer to the server for `duration` and return
+    /// the sender's statistics along with the receiver's report.
+    ///
+    /// The transfer goes over a new connection to the control port, which
+    /// gets through wherever the control connection does.
+    pub fn run_tcp(
+        &mut self,
+        duration: Duration,
+    ) -> Result<(TcpStats, TcpReport), String> {
+        let server = self.ctrl_sk.peer_addr().map_err(|e| e.to_string())?;
+        let mut sk = TcpStream::connect(server).map_err(|e| e.to_string())?;
+        sk.send_msg(ControlMessage::RequestTcpFlow)?;
+        match sk.recv_msg()? {
+            ControlMessage::ExpectTcpFlow => (),
+            ControlMessage::Refused(reason) => return Err(reason),
+            _ => return Err("unknown control message received".to_string()),
+        }
+        let stats =
+            tcp::send_bulk(&mut sk, duration).map_err(|e| e.to_string())?;
+        match sk.recv_msg()? {
+            ControlMessage::TcpReport(r) => Ok((stats, r)),
+            _ => Err("unknown control message received".to_string()),
+        }
+    }
+
+    fn terminate_epoch(
+        &mut self,
+        port: u16,
+        epoch: u32,
+    ) -> Result<SequenceReport, String> {
+        self.ctrl_sk.send_msg(ControlMessage::TerminateEpoch(
+            port,
+            epoch,
+            self.series_interval,
+        ))?;
+        match self.ctrl_sk.recv_msg()? {
+            ControlMessage::Report(mut r) => {
+                if let Some(ref mut delay) = r.delay {
+                    delay.clock = self.clock;
+                }
+                Ok(r)
+            }
+            _ => Err("unknown control message received".to_string()),
+        }
+    }
+
+    /// Attach the clock offset to the report of a flow from the server,
+    /// whose receiver is the client.
+    fn with_server_clock(&self, mut r: SequenceReport) -> SequenceReport {
+        if let Some(ref mut delay) = r.delay {
+            delay.clock = self.clock.map(|c| ClockOffset {
+                offset_ns: -c.offset_ns,
+                ..c
+            });
+        }
+        r
+    }
+
+    fn terminate_flow(&mut self, port: u16) -> Result<(), String> {
+        self.ctrl_sk.send_msg(ControlMessage::TerminateFlow(port))?;
+        match self.ctrl_sk.recv_msg()? {
+            ControlMessage::Report(_) => Ok(()),
+            _ => Err("unknown control message received".to_string()),
+        }
+    }
+
+    pub fn close(mut self) -> Result<(), String> {
+        let mut ports = vec![self.port];
+        ports.extend(self.parallel.iter().map(|flow| flow.1));
+        for port in ports {
+            self.terminate_flow(port)?;
+        }
+        Ok(())
+    }
+}
+
+/// A flow from the server to the client.
+pub struct Downstream {
+    sk: UdpSocket,
+    /// server port the flow comes from
+    port: u16,
+    duration: Duration,
+    /// interval of the throughput series
+    interval: Duration,
+}
+
+impl Downstream {
+    /// Receive the flow until it is over and return the receiver's report.
+    pub fn receive(self) -> Result<SequenceReport, String> {
+        self.sk
+            .set_read_timeout(Some(DOWNSTREAM_LINGER))
+            .map_err(|e| e.to_string())?;
+        let mut receiver = Receiver::from_socket(self.sk, RECV_BATCH);
+        let mut tracker = FlowTracker::new();
+        let start = Instant::now();
+        loop {
+            let cnt = match receiver.recv() {
+                Ok(cnt) => cnt,
+                Err(ref e) if nat::is_timeout(e) => {
+                    if start.elapsed() > self.duration {
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e.to_string()),
+            };
+            for idx in 0..cnt {
+                let (data, ts) = receiver.datagram(idx);
+                // late replies to the punch are no flow datagrams
+                if let Ok(payload) =
+                    serde_json::from_slice::<SequencedPayload>(data)
+                {
+                    tracker.track(
+                        &payload,
+                        data.len(),
+                        ts,
+                        receiver.tos(idx),
+                    );
+                }
+            }
+        }
+        Ok(tracker.report(self.interval))
+    }
+}
+
+/// A flow the server expects.
+struct ServerFlow {
+    port: u16,
+    /// id of the flow, if the port is shared with other flows
+    id: Option<u16>,
+}
+
+impl ServerFlow {
+    /// What the server knows the flow by in control messages.
+    fn key(&self) -> u16 {
+        self.id.unwrap_or(self.port)
+    }
+}
+
+/// Ask the server for a flow and connect a socket to its port.
+///
+/// The socket punches a hole through NATs on the way, so that the server
+/// can send back to it. Returns the address the server sees it at, unless
+/// that fails.
+fn request_flow(
+    ctrl_sk: &mut TcpStream,
+    server: IpAddr,
+) -> Result<(UdpSocket, ServerFlow, Option<SocketAddr>), String> {
+    let flow = request_port(ctrl_sk)?;
+    let sk = UdpSocket::bind((unspecified(server), 0))
+        .map_err(|e| e.to_string())?;
+    sk.connect((server, flow.port)).map_err(|e| e.to_string())?;
+    let mapped = nat::punch(&sk, None, flow.key())
+        .map_err(|e| println!("flow to port {}: {}", flow.port, e))
+        .ok();
+    Ok((sk, flow, mapped))
+}
+
+/// Ask the server for a flow and return its port.
+fn request_port(ctrl_sk: &mut TcpStream) -> Result<ServerFlow, String> {
+    ctrl_sk.send_msg(ControlMessage::RequestFlow)?;
+    loop {
+        match ctrl_sk.recv_msg()? {
+            ControlMessage::ExpectFlow(port) => {
+                return Ok(ServerFlow { port, id: None })
+            }
+            ControlMessage::ExpectSharedFlow(port, id) => {
+                return Ok(ServerFlow { port, id: Some(id) })
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Unspecified address of the family of `server`.
+///
+/// An IPv6 socket cannot reach IPv4-only hosts on every system.
+fn unspecified(server: IpAddr) -> IpAddr {
+    if server.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    }
+}
+
+/// Print which code points the datagrams of a flow arrived with, if not
+/// the ones they were sent with.
+pub fn print_remarking(tos: u8, r: &SequenceReport) {
+    for (dscp, cnt) in marking::remarked(Dscp::from_tos(tos), &r.tos) {
+        println!("{} datagrams remarked to DSCP {}", cnt, dscp);
+    }
+    let cleared = marking::ecn_cleared(Ecn::from_tos(tos), &r.tos);
+    if cleared > 0 {
+        println!("{} datagrams lost their ECN marking", cleared);
+    }
+}
+
+/// A sender that falls further behind the requested rate than this limits
+/// the measurement rather than the network.
+const MIN_SENDER_ACCURACY: f64 = 0.95;
+
+/// Search the highest packet rate that passes without loss in
+/// `direction`, starting at `start_pps`.
+///
+/// Datagrams marked with congestion experienced count as lost, so with ECN
+/// the search stops where a shaper starts marking.
+pub fn find_max_pps(
+    session: &mut Session,
+    direction: Direction,
+    pktlen: usize,
+    start_pps: u32,
+) -> Result<u32, String> {
+    let mut pps = start_pps.max(1);
+    let secs = 3;
+    let mut highest_pps: Option<u32> = None;
+    let mut no_update_iters = 0;
+
+    loop {
+        println!("run {} flow with pps {}", direction, pps);
+        let (stats, r) = session.run_directed(
+            direction,
             pps,
             pktlen,
             Duration::from_secs(secs),
-            // XXX this whole concept doesn't look very efficient
-            move |mut buf: Box<[u8]>| {
-                let payload = SequencedPayload {
-                    seq: seq.next_seq(),
-                };
-                payload.flatten_into(&mut buf);
-                Ok(buf)
-            },
-            sender,
+        )?;
+        println!(
+            "sent {} ({:.2}% of requested), jitter {:?}, \
+             max lateness {:?}, {} underruns, {} send errors",
+            session.framing.rates(stats.pacing.achieved_pps, pktlen),
+            stats.pacing.accuracy * 100.0,
+            stats.pacing.jitter,
+            stats.max_lateness,
+            stats.underruns,
+            stats.send_errors
         );
-        println!("run flow with pps {}", pps);
-        let underruns = flow.start_xmit();
-        if underruns > 0 {
+        if stats.pacing.accuracy < MIN_SENDER_ACCURACY {
             return Err(format!(
                 "Could not generate the requested rate of {} pps",
                 pps
             ));
         }
 
-        ctrl_sk.send_msg(ControlMessage::TerminateFlow(udp_port))?;
-        match ctrl_sk.recv_msg() {
-            Ok(ControlMessage::Report(r)) => {
-                // println!("{:?}", r);
-                let next_pps;
-                let missing_sum = r
-                    .missing
-                    .iter()
-                    .map(|(a, b)| (b + 1) - a)
-                    .fold(0, |a, b| a + b);
-                println!("missing_sum={}", missing_sum);
-                let lost_pps =
-                    (missing_sum + (secs as u32) - 1) / (secs as u32);
-                let _passed_pps = pps - lost_pps;
-                let passed_pps =
-                    (r.cnt - r.dups + (secs as u32) - 1) / (secs as u32);
-                println!("pps {} expected {}", passed_pps, _passed_pps);
-                if passed_pps > highest_pps.unwrap_or_default()
-                    || lost_pps == 0
-                {
-                    highest_pps = Some(passed_pps);
-                    next_pps = passed_pps * 2;
-                } else {
-                    no_update_iters += 1;
-                    // retry slightly above the last limit
-                    next_pps = passed_pps + (lost_pps + 1) / 2;
-                }
-                if no_update_iters >= 3 {
-                    println!(
-                        "determined rate {} B/s",
-                        highest_pps.unwrap_or_default() as u64
-                            * pktlen as u64
-                    );
-                    return Ok(highest_pps.unwrap_or_default());
-                } else {
-                    pps = next_pps;
-                }
-            }
-            _ => return Err("unknown control message received".to_string()),
+        // println!("{:?}", r);
+        let next_pps;
+        let missing_sum = r
+            .missing
+            .iter()
+            .map(|(a, b)| (b + 1) - a)
+            .fold(0, |a, b| a + b)
+            // refused by our own socket, not lost on the way
+            .saturating_sub(stats.send_errors)
+            + r.ce;
+        println!("missing_sum={} (ce={})", missing_sum, r.ce);
+        print_remarking(session.tos, &r);
+        let lost_pps = (missing_sum + (secs as u32) - 1) / (secs as u32);
+        let _passed_pps = pps - lost_pps;
+        let passed = r.passed();
+        let passed_pps = (passed + (secs as u32) - 1) / (secs as u32);
+        println!(
+            "pps {} expected {} spacing {}",
+            passed_pps,
+            _passed_pps,
+            r.arrival.spacing_pps()
+        );
+        if passed_pps > highest_pps.unwrap_or_default() || lost_pps == 0 {
+            highest_pps = Some(passed_pps);
+            next_pps = passed_pps * 2;
+        } else {
+            no_update_iters += 1;
+            // retry slightly above the last limit
+            next_pps = passed_pps + (lost_pps + 1) / 2;
+        }
+        if no_update_iters >= 3 {
+            let highest = highest_pps.unwrap_or_default();
+            println!(
+                "determined rate {}",
+                session.framing.rates(highest.into(), pktlen)
+            );
+            return Ok(highest_pps.unwrap_or_default());
+        } else {
+            pps = next_pps;
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::arrival::ArrivalTracker;
+    use super::clock::{drift, estimate, Exchange};
+    use super::delay::{DelayTracker, RttReport};
+    use super::family::{compare, FamilyPath};
+    use super::link::fingerprint;
+    use super::marking::{ecn_cleared, remarked, TosReport};
+    use super::mtu::{hint, largest, search};
+    use super::parallel::{classify, fairness, Shaping};
     use super::sequence::{ReSequencer, Sequencer};
+    use super::series::{ThroughputTracker, SERIES_INTERVAL};
+    use super::size::{
+        delivery, estimate_from_sizes, SizeDelivery, SizeReport,
+    };
+    use super::tracker::FlowTracker;
+    use super::train::{CapacityEstimate, TrainReport};
+    use super::transport::udp_throttled;
+    use super::SequencedPayload;
+    use sizes::MIN_LEN;
     use std;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use tos::{Dscp, Ecn};
 
     #[test]
     fn seq_instance() {
@@ -141,6 +1037,24 @@ mod tests {
         assert_eq!(s, u8::default());
     }
 
+    #[test]
+    fn payload_min_len() {
+        let largest = || SequencedPayload {
+            epoch: std::u32::MAX,
+            seq: std::u32::MAX,
+            train: Some(std::u32::MAX),
+            flow: Some(std::u16::MAX),
+            sent_ns: Some(std::u64::MAX),
+        };
+        let mut buf = [0; MIN_LEN];
+        largest().flatten_into(&mut buf).expect("fit header");
+        let payload: SequencedPayload =
+            super::serde_json::from_slice(&buf).expect("parse payload");
+        assert_eq!(payload.sent_ns, Some(std::u64::MAX));
+        // a header cut short would pass for a lost datagram
+        assert!(largest().flatten_into(&mut buf[1..]).is_err());
+    }
+
     #[test]
     fn reseq_instance() {
         let _reseq: ReSequencer<usize> = ReSequencer::new();
@@ -255,4 +1169,358 @@ mod tests {
         reseq.track(7u32);
         assert_eq!(reseq.missing, []);
     }
+
+    #[test]
+    fn arrival_spacing() {
+        let mut arrival = ArrivalTracker::new();
+        for us in [0, 1000, 2000, 3000, 3050, 9000].iter() {
+            arrival.track(Duration::from_micros(*us));
+        }
+        let r = arrival.report();
+        assert_eq!(r.first_ns, 0);
+        assert_eq!(r.last_ns, 9_000_000);
+        assert_eq!(r.median_gap_ns, 1_000_000);
+        assert_eq!(r.spacing_pps(), 1000);
+    }
+
+    #[test]
+    fn train_capacity() {
+        // 1 MB/s with 28 bytes of overhead
+        let gap = |len: usize| (len as u64 + 28) * 1000;
+        let mut trains = vec![];
+        for (id, len) in [400, 800, 1200, 800].iter().enumerate() {
+            trains.push(TrainReport {
+                id: id as u32,
+                len: *len,
+                cnt: 11,
+                dispersion_ns: 10 * gap(*len),
+            });
+        }
+        // lossy trains are ignored
+        trains.push(TrainReport {
+            id: 4,
+            len: 400,
+            cnt: 5,
+            dispersion_ns: 1,
+        });
+
+        let estimate =
+            CapacityEstimate::from_trains(&trains, 11).expect("estimate");
+        assert!((estimate.bytes_per_sec - 1e6).abs() < 1.0);
+        assert!((estimate.overhead - 28.0).abs() < 0.01);
+        assert_eq!(estimate.pps(972), 1000);
+    }
+
+    #[test]
+    fn size_delivery() {
+        // back-to-back at 1 MB/s with 28 bytes of overhead
+        let gap = |len: usize| (len as u64 + 28) * 1000;
+        let mut tracker = FlowTracker::new();
+        let mut sent = BTreeMap::new();
+        let mut ts = 0;
+        for (seq, len) in [100, 500, 1000, 100, 500, 1000, 100]
+            .iter()
+            .cycle()
+            .take(70)
+            .enumerate()
+        {
+            *sent.entry(*len).or_insert(0) += 1;
+            ts += gap(*len);
+            if seq % 7 == 2 {
+                // lose every other 1000 bytes datagram
+                continue;
+            }
+            let payload = SequencedPayload {
+                epoch: 0,
+                seq: seq as u32,
+                train: None,
+                flow: None,
+                sent_ns: None,
+            };
+            tracker.track(&payload, *len, Duration::from_nanos(ts), None);
+        }
+
+        let r = tracker.report(SERIES_INTERVAL);
+        let sizes = delivery(&sent, &r.sizes);
+        assert_eq!(
+            sizes[0],
+            SizeDelivery {
+                len: 100,
+                sent: 30,
+                received: 30,
+            }
+        );
+        assert_eq!((sizes[1].sent, sizes[1].received), (20, 20));
+        assert_eq!((sizes[2].sent, sizes[2].received), (20, 10));
+        assert_eq!(sizes[2].ratio(), 0.5);
+
+        let estimate = estimate_from_sizes(&r.sizes).expect("estimate");
+        assert!((estimate.bytes_per_sec - 1e6).abs() < 1.0);
+        assert!((estimate.overhead - 28.0).abs() < 0.01);
+
+        // paced below the bottleneck, the spacing does not follow the sizes
+        let paced: Vec<SizeReport> = [(1000, 5_000_000), (4000, 5_100_000)]
+            .iter()
+            .map(|&(len, median_gap_ns)| SizeReport {
+                len,
+                cnt: 100,
+                median_gap_ns,
+            })
+            .collect();
+        assert!(estimate_from_sizes(&paced).is_none());
+    }
+
+    #[test]
+    fn parallel_shaping() {
+        assert_eq!(fairness(&[10.0, 10.0, 10.0]), 1.0);
+        assert_eq!(fairness(&[30.0, 0.0, 0.0]), 1.0 / 3.0);
+
+        // each flow gets the rate of a single one
+        assert_eq!(classify(100.0, &[100.0, 95.0, 98.0]), Shaping::PerFlow);
+        // the flows share the rate of a single one
+        assert_eq!(
+            classify(90.0, &[30.0, 31.0, 29.0]),
+            Shaping::FairAggregate
+        );
+        assert_eq!(classify(90.0, &[70.0, 15.0, 5.0]), Shaping::Aggregate);
+    }
+
+    #[test]
+    fn tos_remarking() {
+        let received = [
+            TosReport { tos: 0x00, cnt: 3 },
+            TosReport { tos: 0xb8, cnt: 5 },
+            // ECN bits do not count as remarking
+            TosReport { tos: 0xbb, cnt: 1 },
+            TosReport { tos: 0x03, cnt: 2 },
+        ];
+        assert_eq!(remarked(Dscp(46), &received), [(Dscp(0), 5)]);
+        assert_eq!(remarked(Dscp(0), &received), [(Dscp(46), 6)]);
+    }
+
+    #[test]
+    fn ecn_congestion() {
+        let mut tracker = FlowTracker::new();
+        for seq in 0..10u32 {
+            let payload = SequencedPayload {
+                epoch: 0,
+                seq,
+                train: None,
+                flow: None,
+                sent_ns: None,
+            };
+            // a shaper marks every third datagram, one bleaches ECT(0)
+            let tos = match seq {
+                9 => 0x00,
+                seq if seq % 3 == 2 => 0x03,
+                _ => 0x02,
+            };
+            let ts = Duration::from_micros(u64::from(seq));
+            tracker.track(&payload, 100, ts, Some(tos));
+            // a marked duplicate
+            if seq == 5 {
+                tracker.track(&payload, 100, ts, Some(0x03));
+            }
+        }
+        let r = tracker.report(SERIES_INTERVAL);
+        assert_eq!((r.cnt, r.dups, r.ce), (11, 1, 3));
+        assert_eq!(r.passed(), 7);
+        assert_eq!(ecn_cleared(Ecn::Ect0, &r.tos), 1);
+        assert_eq!(ecn_cleared(Ecn::NotEct, &r.tos), 0);
+    }
+
+    #[test]
+    fn family_overheads() {
+        // Ethernet
+        assert_eq!(compare(18, 18), FamilyPath::Native);
+        assert_eq!(compare(18, 22), FamilyPath::Native);
+        // IPv6 in a 6in4 tunnel
+        assert_eq!(compare(18, 38), FamilyPath::Ipv6Tunneled(20));
+        // IPv4 in a DS-Lite tunnel
+        assert_eq!(compare(58, 18), FamilyPath::Ipv4Tunneled(40));
+    }
+
+    #[test]
+    fn mtu_search() {
+        // the largest of the lengths that do not exceed `fits`
+        let probe = |fits: usize| {
+            move |lens: &[usize]| {
+                Ok(lens.iter().cloned().filter(|&len| len <= fits).max())
+            }
+        };
+        let mut rounds = vec![];
+        let mut pppoe = |lens: &[usize]| {
+            rounds.push(lens.to_vec());
+            probe(1464)(lens)
+        };
+        assert_eq!(
+            search(64, 8972, &[1464, 1472], &mut pppoe),
+            Ok(Some(1464))
+        );
+        // the common MTU and then the lengths up to the next one
+        assert_eq!(rounds.len(), 2);
+        assert_eq!(rounds[1], (1465..1472).collect::<Vec<_>>());
+
+        assert_eq!(search(64, 8972, &[], probe(1000)), Ok(Some(1000)));
+        assert_eq!(search(64, 8972, &[], probe(8972)), Ok(Some(8972)));
+        assert_eq!(search(64, 1000, &[], probe(10)), Ok(None));
+        assert_eq!(hint(1492), Some("likely PPPoE"));
+        assert_eq!(hint(1400), None);
+
+        // a receiver that cuts 4000 byte probes short to 2000 bytes
+        let mut tracker = FlowTracker::new();
+        for (seq, &len) in [1472, 1472, 2000, 2000].iter().enumerate() {
+            let payload = SequencedPayload {
+                epoch: 0,
+                seq: seq as u32,
+                train: None,
+                flow: None,
+                sent_ns: None,
+            };
+            tracker.track(&payload, len, Duration::from_millis(1), None);
+        }
+        let r = tracker.report(SERIES_INTERVAL);
+        assert_eq!(largest(&r, &[1472, 4000]), Some(1472));
+        assert_eq!(largest(&r, &[1472, 2000]), Some(2000));
+    }
+
+    #[test]
+    fn link_types() {
+        let links = fingerprint(34, Some(1492));
+        assert_eq!(links[0].0.name, "PPPoE over VDSL2 with VLAN");
+        let total: f64 = links.iter().map(|link| link.1).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+
+        // the MTU tells equal overheads apart
+        assert_eq!(
+            fingerprint(32, Some(1492))[0].0.name,
+            "PPPoE over ADSL, VC-mux"
+        );
+        assert_eq!(
+            fingerprint(32, Some(1500))[0].0.name,
+            "bridged ADSL, LLC/SNAP"
+        );
+        // without it, both are about as likely
+        let links = fingerprint(32, None);
+        assert!((links[0].1 - links[1].1).abs() < 1e-9);
+
+        assert_eq!(fingerprint(19, Some(1500))[0].0.name, "DOCSIS");
+        assert_eq!(
+            fingerprint(57, Some(1460))[0].0.name,
+            "DS-Lite over DOCSIS"
+        );
+    }
+
+    #[test]
+    fn delay_percentiles() {
+        let mut delay = DelayTracker::new();
+        assert_eq!(delay.report(), None);
+        // a clock offset of 5 s and queueing delays of 0 to 99 ms
+        let offset = 5_000_000_000u64;
+        for ms in 0..100u64 {
+            let sent_ns = ms * 10_000_000;
+            let ts = Duration::from_nanos(offset + sent_ns + ms * 1_000_000);
+            delay.track(sent_ns, ts + Duration::from_millis(20));
+        }
+        let r = delay.report().expect("delay report");
+        assert_eq!(r.min_ns, 5_020_000_000);
+        assert_eq!(r.p50_ns, 49_000_000);
+        assert_eq!(r.p95_ns, 94_000_000);
+        assert_eq!(r.p99_ns, 98_000_000);
+    }
+
+    #[test]
+    fn clock_offset() {
+        let ms = 1_000_000u64;
+        // the server's clock is 3 s ahead, 10 ms up and 20 ms down
+        let exchange = |t1: u64, queued: u64| Exchange {
+            t1,
+            t2: t1 + 3000 * ms + 10 * ms + queued,
+            t3: t1 + 3000 * ms + 11 * ms + queued,
+            t4: t1 + 31 * ms + queued,
+        };
+        let exchanges = [exchange(0, 50 * ms), exchange(100 * ms, 0)];
+        assert_eq!(exchanges[1].rtt(), 30 * ms as i64);
+
+        let offset = estimate(&exchanges).expect("clock offset");
+        assert_eq!(offset.offset_ns, (3000 * ms - 5 * ms) as i64);
+        assert_eq!(offset.uncertainty_ns, 15 * ms);
+        assert_eq!(estimate(&[]), None);
+
+        let r = RttReport::new(3, &exchanges);
+        assert_eq!(r.received, 2);
+        assert_eq!(r.rtt.min_ns, 30 * ms);
+        // the asymmetry is put down to the clocks
+        assert_eq!(r.up.min_ns, 15 * ms);
+        assert_eq!(r.down.min_ns, 15 * ms);
+    }
+
+    #[test]
+    fn clock_drift() {
+        let ms = 1_000_000u64;
+        // the receiver's clock gains 50 ppm over 10 s, datagrams queue for
+        // up to 9 ms, but every window has one that did not
+        let mut delay = DelayTracker::new();
+        let mut samples = vec![];
+        for i in 0..1000u64 {
+            let sent_ns = i * 10 * ms;
+            let queued = (i % 10) * ms;
+            let delay_ns = 20 * ms + sent_ns / 20_000 + queued;
+            delay.track(sent_ns, Duration::from_nanos(sent_ns + delay_ns));
+            samples.push((sent_ns, delay_ns as i64));
+        }
+        let slope = drift(&samples, 1000 * ms);
+        assert!((slope * 1e6 - 50.0).abs() < 0.1);
+
+        let r = delay.report().expect("delay report");
+        assert!((r.drift_ppm - 50.0).abs() < 0.1);
+        assert_eq!(r.min_ns, 20 * ms as i64);
+        // without the correction the drift would add up to 0.5 ms
+        assert!(r.p99_ns <= 9 * ms + 10_000);
+        assert_eq!(r.one_way(), None);
+
+        // a queue that builds up by 1 ms per second is no drift
+        let building: Vec<(u64, i64)> = (0..100)
+            .map(|i| (i * 100 * ms, (20 * ms + i * 100_000) as i64))
+            .collect();
+        assert_eq!(drift(&building, 1000 * ms), 0.0);
+    }
+
+    #[test]
+    fn throughput_series() {
+        let mut throughput = ThroughputTracker::new();
+        assert_eq!(throughput.report(SERIES_INTERVAL), None);
+        // 100 byte datagrams every ms for 200 ms, a policer drops all of
+        // the third 100 ms, then 200 byte datagrams for 50 ms
+        let start = 7_000_000_000u64;
+        for ms in (0..200u64).chain(300..350) {
+            let len = if ms < 300 { 100 } else { 200 };
+            throughput
+                .track(len, Duration::from_nanos(start + ms * 1_000_000));
+        }
+
+        let r = throughput.report(SERIES_INTERVAL).expect("series");
+        assert_eq!(r.interval_ns, 100_000_000);
+        let packets: Vec<u32> = r.samples.iter().map(|s| s.packets).collect();
+        assert_eq!(packets, [100, 100, 0, 50]);
+        assert_eq!(r.samples[0].bytes, 10_000);
+        assert_eq!(r.samples[3].mean_len(), 200);
+        assert_eq!(r.pps(&r.samples[3]), 500.0);
+
+        let r = throughput
+            .report(Duration::from_millis(250))
+            .expect("series");
+        assert_eq!(r.samples.len(), 2);
+        assert_eq!(r.samples[1].packets, 50);
+    }
+
+    #[test]
+    fn udp_throttling() {
+        use bitrate::Bitrate;
+
+        let mbit = |m: u64| Bitrate(m * 1_000_000);
+        assert_eq!(udp_throttled(mbit(50), mbit(45)), None);
+        assert_eq!(udp_throttled(mbit(50), mbit(55)), None);
+        assert_eq!(udp_throttled(mbit(10), mbit(40)), Some(0.25));
+    }
 }
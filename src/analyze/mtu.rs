@@ -0,0 +1,114 @@
+use analyze::sequence::SequenceReport;
+use analyze::Session;
+use sizes::MIN_LEN;
+
+/// Largest IP packet the discovery tries, a jumbo frame.
+const MAX_MTU: usize = 9000;
+/// Path MTUs of common links, tried first.
+const COMMON_MTUS: [usize; 9] =
+    [1280, 1400, 1452, 1460, 1476, 1480, 1492, 1500, MAX_MTU];
+/// Lengths tried per round once the common MTUs are ruled out.
+const ROUND_LENS: usize = 16;
+/// Datagrams sent per length; one of them has to arrive.
+const PROBE_CNT: u32 = 2;
+
+/// Largest length from `lo` to `hi` that fits, given that all shorter ones
+/// fit, too. `None` if not even `lo` fits.
+///
+/// Each round hands a set of lengths to `probe`, which returns the largest
+/// of them that fits. The first round tries `first`.
+pub fn search<F>(
+    lo: usize,
+    hi: usize,
+    first: &[usize],
+    mut probe: F,
+) -> Result<Option<usize>, String>
+where
+    F: FnMut(&[usize]) -> Result<Option<usize>, String>,
+{
+    // largest length known to fit and smallest known not to
+    let (mut good, mut bad) = (None, hi + 1);
+    let mut lens = first.to_vec();
+    lens.push(lo);
+    lens.push(hi);
+    loop {
+        let from = good.map_or(lo, |good| good + 1);
+        lens.retain(|&len| len >= from && len < bad);
+        lens.sort();
+        lens.dedup();
+        if lens.is_empty() {
+            return Ok(good);
+        }
+        match probe(&lens)? {
+            Some(len) => {
+                good = Some(len);
+                bad = lens.iter().cloned().find(|&l| l > len).unwrap_or(bad);
+            }
+            None => bad = lens[0],
+        }
+        let from = good.map_or(lo, |good| good + 1);
+        lens = spread(from, bad, ROUND_LENS);
+    }
+}
+
+/// Up to `n` lengths evenly spread from `from` to below `to`.
+fn spread(from: usize, to: usize, n: usize) -> Vec<usize> {
+    if to <= from {
+        return vec![];
+    }
+    let last = to - 1;
+    if last - from < n {
+        return (from..=last).collect();
+    }
+    (0..n).map(|i| from + (last - from) * i / (n - 1)).collect()
+}
+
+/// Link that typically comes with a path MTU.
+pub fn hint(mtu: usize) -> Option<&'static str> {
+    match mtu {
+        1500 => Some("plain Ethernet"),
+        1492 => Some("likely PPPoE"),
+        1480 => Some("likely 6in4 or 6rd"),
+        1476 => Some("likely GRE"),
+        1460 => Some("likely DS-Lite"),
+        1452 => Some("likely DS-Lite over PPPoE"),
+        1280 => Some("the IPv6 minimum, likely a tunnel"),
+        _ => None,
+    }
+}
+
+/// Largest datagram of a probing round of `lens` that reached the server
+/// whole. A length that was not sent is a datagram cut short.
+pub fn largest(r: &SequenceReport, lens: &[usize]) -> Option<usize> {
+    r.sizes
+        .iter()
+        .filter(|s| s.cnt > 0 && lens.contains(&s.len))
+        .map(|s| s.len)
+        .max()
+}
+
+/// Find the largest payload that reaches the server unfragmented.
+///
+/// Datagrams with the DF bit set are sent at a set of lengths per round
+/// and the server's report tells which of them arrived.
+pub fn discover_pmtu(session: &mut Session) -> Result<usize, String> {
+    let framing = session.framing();
+    let headers = framing.l3_len(0);
+    let max_len = MAX_MTU - headers;
+    let first: Vec<usize> =
+        COMMON_MTUS.iter().map(|mtu| mtu - headers).collect();
+    let len = search(MIN_LEN, max_len, &first, |lens| {
+        Ok(largest(&session.probe_lens(lens, PROBE_CNT)?, lens))
+    })?
+    .ok_or("no probe reached the server")?;
+
+    let mtu = framing.l3_len(len);
+    if len == max_len {
+        println!("path MTU {} or more", mtu);
+    } else if let Some(hint) = hint(mtu) {
+        println!("path MTU {}, {}", mtu, hint);
+    } else {
+        println!("path MTU {}", mtu);
+    }
+    Ok(len)
+}
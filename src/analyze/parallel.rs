@@ -0,0 +1,109 @@
+use std::str::FromStr;
+use tos::Dscp;
+
+/// Parallel flows that get less than this share of their combined rate
+/// hit a limit on the aggregate.
+const SCALING_MARGIN: f64 = 0.9;
+/// Fairness index above which flows are considered to share a limit
+/// evenly.
+const FAIR_INDEX: f64 = 0.9;
+
+/// How a shaper treats parallel flows.
+#[derive(Debug, PartialEq)]
+pub enum Shaping {
+    /// each flow passes at the rate of a single one
+    PerFlow,
+    /// the flows share a common limit evenly, e.g. with fair queuing
+    FairAggregate,
+    /// the flows share a common limit unevenly, e.g. with a FIFO
+    Aggregate,
+}
+
+/// Jain's fairness index: 1 if all rates are equal, `1/n` if a single
+/// flow takes everything.
+pub fn fairness(rates: &[f64]) -> f64 {
+    let sum: f64 = rates.iter().sum();
+    let sum_sq: f64 = rates.iter().map(|r| r * r).sum();
+    if sum_sq == 0.0 {
+        1.0
+    } else {
+        sum * sum / (rates.len() as f64 * sum_sq)
+    }
+}
+
+/// Tell per-flow from aggregate shaping by the rates of parallel flows
+/// compared to the rate of a `single` flow on its own.
+pub fn classify(single: f64, rates: &[f64]) -> Shaping {
+    let aggregate: f64 = rates.iter().sum();
+    if aggregate >= SCALING_MARGIN * single * rates.len() as f64 {
+        Shaping::PerFlow
+    } else if fairness(rates) >= FAIR_INDEX {
+        Shaping::FairAggregate
+    } else {
+        Shaping::Aggregate
+    }
+}
+
+/// Parallel flows, each with the DSCP class it is marked with or `None`
+/// to keep the session's marking.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flows(pub Vec<Option<Dscp>>);
+
+impl Flows {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether any flow is marked with a class of its own.
+    pub fn classified(&self) -> bool {
+        self.0.iter().any(Option::is_some)
+    }
+}
+
+/// Parses a number of flows, or entries of `<n>:<dscp>` and `<n>` like
+/// `2:ef,2:be`.
+impl FromStr for Flows {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Flows, String> {
+        let mut flows = vec![];
+        for entry in s.split(',') {
+            let mut parts = entry.splitn(2, ':');
+            let cnt = parts.next().unwrap_or("");
+            let cnt = cnt
+                .parse::<usize>()
+                .map_err(|_| format!("invalid number of flows: {}", cnt))?;
+            let class = match parts.next() {
+                Some(dscp) => Some(dscp.parse::<Dscp>()?),
+                None => None,
+            };
+            flows.extend((0..cnt).map(|_| class));
+        }
+        if flows.is_empty() {
+            return Err("no flows".to_string());
+        }
+        Ok(Flows(flows))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flows_parse() {
+        assert_eq!("3".parse(), Ok(Flows(vec![None; 3])));
+        assert_eq!(
+            "2:ef,1:be,1".parse(),
+            Ok(Flows(vec![
+                Some(Dscp(46)),
+                Some(Dscp(46)),
+                Some(Dscp(0)),
+                None,
+            ]))
+        );
+        assert!("0".parse::<Flows>().is_err());
+        assert!("2:xx".parse::<Flows>().is_err());
+        assert!("ef".parse::<Flows>().is_err());
+    }
+}
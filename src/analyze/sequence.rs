@@ -1,3 +1,9 @@
+use analyze::arrival::ArrivalReport;
+use analyze::delay::DelayReport;
+use analyze::marking::TosReport;
+use analyze::series::ThroughputSeries;
+use analyze::size::SizeReport;
+use analyze::train::TrainReport;
 use std::num::Wrapping;
 use std::ops::Add;
 use std::ops::Sub;
@@ -8,6 +14,29 @@ pub struct SequenceReport {
     pub missing: Vec<(u32, u32)>,
     pub dups: u32,
     pub cnt: u32,
+    pub arrival: ArrivalReport,
+    #[serde(default)]
+    pub trains: Vec<TrainReport>,
+    #[serde(default)]
+    pub sizes: Vec<SizeReport>,
+    #[serde(default)]
+    pub tos: Vec<TosReport>,
+    /// datagrams that arrived marked with congestion experienced
+    #[serde(default)]
+    pub ce: u32,
+    /// delays of datagrams stamped with their send time
+    #[serde(default)]
+    pub delay: Option<DelayReport>,
+    /// datagrams and bytes that arrived per interval
+    #[serde(default)]
+    pub series: Option<ThroughputSeries>,
+}
+
+impl SequenceReport {
+    /// Datagrams that arrived once and without a congestion mark.
+    pub fn passed(&self) -> u32 {
+        self.cnt.saturating_sub(self.dups).saturating_sub(self.ce)
+    }
 }
 
 pub struct ReSequencer<T>
@@ -0,0 +1,83 @@
+use analyze::arrival::as_ns;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Default interval of the receiver's throughput series.
+pub const SERIES_INTERVAL: Duration = Duration::from_millis(100);
+/// Arrivals are counted at this resolution, so that the series can be
+/// reported at any multiple of it.
+const SLOT_NS: u64 = 1_000_000;
+
+/// Datagrams and bytes that arrived in one interval of a flow.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ThroughputSample {
+    pub packets: u32,
+    /// payload bytes
+    pub bytes: u64,
+}
+
+impl ThroughputSample {
+    /// Mean payload length of the datagrams.
+    pub fn mean_len(&self) -> usize {
+        self.bytes.checked_div(self.packets.into()).unwrap_or(0) as usize
+    }
+}
+
+/// Throughput of a flow over time at the receiver, one sample per
+/// interval from the first arrival on. Intervals without arrivals have
+/// empty samples.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ThroughputSeries {
+    pub interval_ns: u64,
+    pub samples: Vec<ThroughputSample>,
+}
+
+impl ThroughputSeries {
+    /// Packet rate of a sample.
+    pub fn pps(&self, sample: &ThroughputSample) -> f64 {
+        f64::from(sample.packets) * 1e9 / self.interval_ns as f64
+    }
+}
+
+pub struct ThroughputTracker {
+    first: Option<Duration>,
+    /// datagrams and bytes per slot since the first arrival
+    slots: BTreeMap<u64, ThroughputSample>,
+}
+
+impl ThroughputTracker {
+    pub fn new() -> ThroughputTracker {
+        ThroughputTracker {
+            first: None,
+            slots: BTreeMap::new(),
+        }
+    }
+
+    /// Account for a datagram of `len` bytes that arrived at `ts`.
+    pub fn track(&mut self, len: usize, ts: Duration) {
+        let first = *self.first.get_or_insert(ts);
+        // reordered timestamps count towards the first slot
+        let slot = as_ns(ts.checked_sub(first).unwrap_or_default()) / SLOT_NS;
+        let sample = self.slots.entry(slot).or_default();
+        sample.packets += 1;
+        sample.bytes += len as u64;
+    }
+
+    /// The series at `interval`, rounded to the resolution, if anything
+    /// arrived.
+    pub fn report(&self, interval: Duration) -> Option<ThroughputSeries> {
+        let slots = (as_ns(interval) / SLOT_NS).max(1);
+        let last = *self.slots.keys().next_back()?;
+        let mut samples =
+            vec![ThroughputSample::default(); (last / slots + 1) as usize];
+        for (slot, sample) in &self.slots {
+            let merged = &mut samples[(slot / slots) as usize];
+            merged.packets += sample.packets;
+            merged.bytes += sample.bytes;
+        }
+        Some(ThroughputSeries {
+            interval_ns: slots * SLOT_NS,
+            samples,
+        })
+    }
+}
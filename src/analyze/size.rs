@@ -0,0 +1,65 @@
+use analyze::train::CapacityEstimate;
+use std::collections::BTreeMap;
+
+/// Datagrams of one size class as seen by the receiver.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SizeReport {
+    /// size of the datagrams in bytes
+    pub len: usize,
+    pub cnt: u32,
+    /// median spacing to the preceding datagram in ns
+    pub median_gap_ns: u64,
+}
+
+/// Delivery of one size class of a mixed flow.
+#[derive(Debug, PartialEq)]
+pub struct SizeDelivery {
+    pub len: usize,
+    pub sent: u64,
+    pub received: u32,
+}
+
+impl SizeDelivery {
+    /// Share of the sent datagrams that were received.
+    pub fn ratio(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            f64::from(self.received) / self.sent as f64
+        }
+    }
+}
+
+/// Combine the sender's datagram counts per size with the receiver's
+/// report.
+pub fn delivery(
+    sent: &BTreeMap<usize, u64>,
+    received: &[SizeReport],
+) -> Vec<SizeDelivery> {
+    sent.iter()
+        .map(|(&len, &sent)| SizeDelivery {
+            len,
+            sent,
+            received: received
+                .iter()
+                .find(|size| size.len == len)
+                .map_or(0, |size| size.cnt),
+        })
+        .collect()
+}
+
+/// Fit the capacity to the spacing of the size classes of a mixed flow.
+///
+/// The spacing only reflects the bottleneck if the flow exceeds it, so
+/// that the datagrams leave it back-to-back. Otherwise it follows the
+/// sender's pacing rather than the sizes, and a fit with an overhead
+/// beyond the longest datagram is dropped.
+pub fn estimate_from_sizes(sizes: &[SizeReport]) -> Option<CapacityEstimate> {
+    let points: Vec<(f64, f64)> = sizes
+        .iter()
+        .filter(|size| size.median_gap_ns > 0)
+        .map(|size| (size.len as f64, size.median_gap_ns as f64))
+        .collect();
+    let longest = points.iter().map(|p| p.0).fold(0.0, f64::max);
+    CapacityEstimate::fit(&points).filter(|e| e.overhead <= longest)
+}
@@ -0,0 +1,116 @@
+use analyze::arrival::{as_ns, median, ArrivalTracker};
+use analyze::delay::DelayTracker;
+use analyze::marking::TosReport;
+use analyze::sequence::{ReSequencer, SequenceReport};
+use analyze::series::ThroughputTracker;
+use analyze::size::SizeReport;
+use analyze::train::TrainReport;
+use analyze::SequencedPayload;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tos::Ecn;
+
+/// Receiver side state of a single flow epoch.
+pub struct FlowTracker {
+    reseq: ReSequencer<u32>,
+    arrival: ArrivalTracker,
+    delay: DelayTracker,
+    throughput: ThroughputTracker,
+    /// first arrival and report of each packet train
+    trains: BTreeMap<u32, (Duration, TrainReport)>,
+    /// datagrams and gaps to their predecessor per size
+    sizes: BTreeMap<usize, (u32, Vec<u64>)>,
+    /// datagrams per received TOS byte
+    tos: BTreeMap<u8, u32>,
+    ce: u32,
+}
+
+impl FlowTracker {
+    pub fn new() -> FlowTracker {
+        FlowTracker {
+            reseq: ReSequencer::new(),
+            arrival: ArrivalTracker::new(),
+            delay: DelayTracker::new(),
+            throughput: ThroughputTracker::new(),
+            trains: BTreeMap::new(),
+            sizes: BTreeMap::new(),
+            tos: BTreeMap::new(),
+            ce: 0,
+        }
+    }
+
+    /// Account for a datagram of `len` bytes that arrived at `ts` with
+    /// `tos`, if known.
+    pub fn track(
+        &mut self,
+        payload: &SequencedPayload,
+        len: usize,
+        ts: Duration,
+        tos: Option<u8>,
+    ) {
+        let dups = self.reseq.dups;
+        self.reseq.track(payload.seq);
+        let dup = self.reseq.dups > dups;
+        let gap = self.arrival.track(ts);
+        if let Some(sent_ns) = payload.sent_ns {
+            self.delay.track(sent_ns, ts);
+        }
+        self.throughput.track(len, ts);
+        let size = self.sizes.entry(len).or_default();
+        size.0 += 1;
+        size.1.extend(gap);
+        if let Some(tos) = tos {
+            *self.tos.entry(tos).or_default() += 1;
+            // a marked duplicate is counted as a duplicate only
+            if Ecn::from_tos(tos) == Ecn::Ce && !dup {
+                self.ce += 1;
+            }
+        }
+
+        if let Some(id) = payload.train {
+            let &mut (first, ref mut train) =
+                self.trains.entry(id).or_insert_with(|| {
+                    let train = TrainReport {
+                        id,
+                        len,
+                        cnt: 0,
+                        dispersion_ns: 0,
+                    };
+                    (ts, train)
+                });
+            train.cnt += 1;
+            if ts > first {
+                train.dispersion_ns = as_ns(ts - first);
+            }
+        }
+    }
+
+    /// Summary of the flow epoch with a throughput series at `interval`.
+    pub fn report(&self, interval: Duration) -> SequenceReport {
+        SequenceReport {
+            last_seq: self.reseq.last_seq.unwrap_or(0),
+            missing: self.reseq.missing.clone(),
+            dups: self.reseq.dups,
+            cnt: self.reseq.cnt,
+            arrival: self.arrival.report(),
+            trains: self.trains.values().map(|t| t.1.clone()).collect(),
+            sizes: self
+                .sizes
+                .iter()
+                .map(|(&len, &(cnt, ref gaps))| SizeReport {
+                    len,
+                    cnt,
+                    median_gap_ns: median(gaps),
+                })
+                .collect(),
+            tos: self
+                .tos
+                .iter()
+                .map(|(&tos, &cnt)| TosReport { tos, cnt })
+                .collect(),
+            ce: self.ce,
+            delay: self.delay.report(),
+            series: self.throughput.report(interval),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use analyze::Session;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Dispersion of a train of back-to-back datagrams at the receiver.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrainReport {
+    pub id: u32,
+    /// size of the train's datagrams in bytes
+    pub len: usize,
+    pub cnt: u32,
+    /// time between the first and the last arrival in ns
+    pub dispersion_ns: u64,
+}
+
+impl TrainReport {
+    /// Spacing of consecutive datagrams in ns.
+    pub fn gap_ns(&self) -> Option<f64> {
+        if self.cnt < 2 {
+            None
+        } else {
+            Some(self.dispersion_ns as f64 / (self.cnt - 1) as f64)
+        }
+    }
+}
+
+/// Bottleneck capacity as seen by datagrams of different sizes.
+#[derive(Debug)]
+pub struct CapacityEstimate {
+    /// capacity in bytes per second, including the overhead
+    pub bytes_per_sec: f64,
+    /// per datagram overhead in bytes on top of the payload
+    pub overhead: f64,
+}
+
+impl CapacityEstimate {
+    /// Packet rate that saturates the bottleneck with `pktlen` datagrams.
+    pub fn pps(&self, pktlen: usize) -> u32 {
+        let size = pktlen as f64 + self.overhead.max(0.0);
+        (self.bytes_per_sec / size) as u32
+    }
+
+    /// Fit `gap = (len + overhead) / capacity` to the train reports.
+    ///
+    /// Trains that lost datagrams are ignored. Per size, the median gap is
+    /// used to be robust against cross traffic.
+    pub fn from_trains(trains: &[TrainReport], cnt: u32) -> Option<Self> {
+        let mut gaps: BTreeMap<usize, Vec<f64>> = BTreeMap::new();
+        for train in trains.iter().filter(|t| t.cnt == cnt) {
+            if let Some(gap) = train.gap_ns() {
+                gaps.entry(train.len).or_default().push(gap);
+            }
+        }
+        let points: Vec<(f64, f64)> = gaps
+            .iter_mut()
+            .map(|(len, gaps)| {
+                gaps.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                (*len as f64, gaps[gaps.len() / 2])
+            })
+            .collect();
+        CapacityEstimate::fit(&points)
+    }
+
+    /// Least squares fit of `(len, gap in ns)` points.
+    pub fn fit(points: &[(f64, f64)]) -> Option<Self> {
+        if points.len() < 2 {
+            return None;
+        }
+
+        let n = points.len() as f64;
+        let mean_len = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_gap = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let cov: f64 = points
+            .iter()
+            .map(|p| (p.0 - mean_len) * (p.1 - mean_gap))
+            .sum();
+        let var: f64 = points.iter().map(|p| (p.0 - mean_len).powi(2)).sum();
+        let slope = cov / var;
+        if slope <= 0.0 {
+            return None;
+        }
+        let intercept = mean_gap - slope * mean_len;
+
+        Some(CapacityEstimate {
+            bytes_per_sec: 1e9 / slope,
+            overhead: intercept / slope,
+        })
+    }
+}
+
+/// Datagram sizes of the probing trains.
+const TRAIN_SIZES: [usize; 3] = [400, 800, 1200];
+/// Datagrams per train.
+const TRAIN_CNT: u32 = 32;
+/// Trains per datagram size.
+const TRAIN_ROUNDS: usize = 8;
+
+/// Estimate the bottleneck capacity from the dispersion of packet trains.
+///
+/// This takes a fraction of the time of `find_max_pps`. Shapers with a
+/// burst allowance larger than a train let it pass at line rate, so the
+/// estimate is best used as a starting point for the search.
+pub fn estimate_capacity(
+    session: &mut Session,
+) -> Result<CapacityEstimate, String> {
+    let mut trains = vec![];
+    for _ in 0..TRAIN_ROUNDS {
+        for len in TRAIN_SIZES.iter() {
+            trains.push((*len, TRAIN_CNT));
+        }
+    }
+
+    let report = session.run_trains(&trains, Duration::from_millis(20))?;
+    let estimate = CapacityEstimate::from_trains(&report.trains, TRAIN_CNT)
+        .ok_or("packet trains do not allow an estimate")?;
+    println!("{:?}", estimate);
+    let overhead = estimate.overhead.round() as i64;
+    println!("overhead {}", session.framing().link_overhead(overhead));
+    Ok(estimate)
+}
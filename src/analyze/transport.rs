@@ -0,0 +1,16 @@
+use bitrate::Bitrate;
+
+/// Share by which the TCP goodput may exceed the UDP one before UDP is
+/// considered throttled. TCP usually gets less, due to its congestion
+/// control and acknowledgements.
+const THROTTLE_MARGIN: f64 = 0.2;
+
+/// Whether UDP is throttled compared to TCP on the same path, going by the
+/// goodput of both. Returns the share of the TCP goodput UDP gets, if so.
+pub fn udp_throttled(udp: Bitrate, tcp: Bitrate) -> Option<f64> {
+    if tcp.0 as f64 > udp.0 as f64 * (1.0 + THROTTLE_MARGIN) {
+        Some(udp.0 as f64 / tcp.0 as f64)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,242 @@
+use sizes::PacketSizes;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+const IPV4_HEADER: usize = 20;
+const IPV6_HEADER: usize = 40;
+const UDP_HEADER: usize = 8;
+/// Ethernet header and FCS
+const ETH_HEADER: usize = 18;
+/// Ethernet pads shorter frames to this length.
+const ETH_MIN_FRAME: usize = 64;
+/// preamble, start of frame delimiter and interframe gap
+const ETH_WIRE: usize = 20;
+
+/// A rate in bits per second.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Bitrate(pub u64);
+
+/// Parses a number of bits per second with an optional `k`, `M` or `G`
+/// suffix.
+impl FromStr for Bitrate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Bitrate, String> {
+        let (num, unit) = match s.char_indices().last() {
+            Some((i, 'k')) | Some((i, 'K')) => (&s[..i], 1e3),
+            Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1e6),
+            Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 1e9),
+            _ => (s, 1.0),
+        };
+        let bits = num
+            .parse::<f64>()
+            .map_err(|e| format!("invalid bit rate {}: {}", s, e))?;
+        if !bits.is_finite() || bits <= 0.0 {
+            return Err(format!("invalid bit rate: {}", s));
+        }
+        Ok(Bitrate((bits * unit) as u64))
+    }
+}
+
+impl fmt::Display for Bitrate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.2} Mbit/s", self.0 as f64 / 1e6)
+    }
+}
+
+/// Rate of a flow at the different layers.
+#[derive(Debug, Clone, Copy)]
+pub struct LayerRates {
+    pub pps: f64,
+    /// IP packets
+    pub l3: Bitrate,
+    /// Ethernet frames
+    pub l2: Bitrate,
+    /// Ethernet frames including preamble and interframe gap
+    pub wire: Bitrate,
+}
+
+impl fmt::Display for LayerRates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.0} pps, L3 {}, L2 {}, wire {}",
+            self.pps, self.l3, self.l2, self.wire
+        )
+    }
+}
+
+/// Per packet overhead of a link relative to the different layers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LinkOverhead {
+    /// on top of the UDP payload
+    pub payload: i64,
+    /// on top of the IP packet, as cake's `overhead` takes it
+    pub l3: i64,
+    /// on top of the Ethernet frame, including its header and FCS
+    pub l2: i64,
+}
+
+impl fmt::Display for LinkOverhead {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes on top of the IP packet, {} on top of the Ethernet \
+             frame, {} on top of the UDP payload",
+            self.l3, self.l2, self.payload
+        )
+    }
+}
+
+/// Bytes a UDP payload occupies at the different layers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Framing {
+    ip_header: usize,
+    /// per packet overhead a target bit rate accounts for on top of the
+    /// IP packet
+    overhead: usize,
+}
+
+impl Framing {
+    pub fn new(ipv6: bool) -> Framing {
+        Framing {
+            ip_header: if ipv6 { IPV6_HEADER } else { IPV4_HEADER },
+            overhead: 0,
+        }
+    }
+
+    pub fn with_overhead(mut self, overhead: usize) -> Framing {
+        self.overhead = overhead;
+        self
+    }
+
+    pub fn l3_len(&self, payload: usize) -> usize {
+        payload + self.ip_header + UDP_HEADER
+    }
+
+    pub fn l2_len(&self, payload: usize) -> usize {
+        ETH_MIN_FRAME.max(self.l3_len(payload) + ETH_HEADER)
+    }
+
+    pub fn wire_len(&self, payload: usize) -> usize {
+        self.l2_len(payload) + ETH_WIRE
+    }
+
+    /// Bytes of a datagram as counted against a target bit rate.
+    pub fn target_len(&self, payload: usize) -> usize {
+        self.l3_len(payload) + self.overhead
+    }
+
+    /// Split an overhead measured on top of the UDP payload by layer.
+    pub fn link_overhead(&self, payload: i64) -> LinkOverhead {
+        let l3 = payload - (self.ip_header + UDP_HEADER) as i64;
+        LinkOverhead {
+            payload,
+            l3,
+            l2: l3 - ETH_HEADER as i64,
+        }
+    }
+
+    /// Packet rate of datagrams drawn from `sizes` that meets `bitrate`.
+    pub fn pps_of_sizes(&self, bitrate: Bitrate, sizes: &PacketSizes) -> u32 {
+        let classes = sizes.classes();
+        let weights: u64 = classes.iter().map(|c| u64::from(c.1)).sum();
+        let bytes: u64 = classes
+            .iter()
+            .map(|c| self.target_len(c.0) as u64 * u64::from(c.1))
+            .sum();
+        (bitrate.0 / 8 * weights).checked_div(bytes).unwrap_or(0) as u32
+    }
+
+    /// Rates of `pps` datagrams of `payload` bytes.
+    pub fn rates(&self, pps: f64, payload: usize) -> LayerRates {
+        let bits = |len: usize| Bitrate((pps * len as f64 * 8.0) as u64);
+        LayerRates {
+            pps,
+            l3: bits(self.l3_len(payload)),
+            l2: bits(self.l2_len(payload)),
+            wire: bits(self.wire_len(payload)),
+        }
+    }
+
+    /// Rates of a flow that sent `sizes` (datagrams per size) in `secs`.
+    pub fn rates_of_sizes(
+        &self,
+        sizes: &BTreeMap<usize, u64>,
+        secs: f64,
+    ) -> LayerRates {
+        let bits = |len: &dyn Fn(usize) -> usize| {
+            let bytes: u64 =
+                sizes.iter().map(|(&l, &cnt)| len(l) as u64 * cnt).sum();
+            Bitrate((bytes as f64 * 8.0 / secs) as u64)
+        };
+        LayerRates {
+            pps: sizes.values().sum::<u64>() as f64 / secs,
+            l3: bits(&|l| self.l3_len(l)),
+            l2: bits(&|l| self.l2_len(l)),
+            wire: bits(&|l| self.wire_len(l)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitrate_parse() {
+        assert_eq!("2000".parse(), Ok(Bitrate(2000)));
+        assert_eq!("1.5k".parse(), Ok(Bitrate(1500)));
+        assert_eq!("50M".parse(), Ok(Bitrate(50_000_000)));
+        assert_eq!("1g".parse(), Ok(Bitrate(1_000_000_000)));
+        assert!("M".parse::<Bitrate>().is_err());
+        assert!("-5M".parse::<Bitrate>().is_err());
+        assert_eq!(Bitrate(12_345_678).to_string(), "12.35 Mbit/s");
+    }
+
+    #[test]
+    fn framing_layers() {
+        let v4 = Framing::new(false);
+        let v6 = Framing::new(true);
+        assert_eq!(v4.l3_len(1472), 1500);
+        assert_eq!(v4.l2_len(1472), 1518);
+        assert_eq!(v4.wire_len(1472), 1538);
+        assert_eq!(v6.l3_len(1452), 1500);
+        // short frames are padded
+        assert_eq!(v4.l2_len(10), 64);
+
+        let rates = v4.rates(1000.0, 1472);
+        assert_eq!(rates.l3, Bitrate(12_000_000));
+        assert_eq!(rates.wire, Bitrate(12_304_000));
+    }
+
+    #[test]
+    fn framing_overhead() {
+        // PPPoE over Ethernet, 18 + 8 bytes on top of the IP packet
+        let v4 = Framing::new(false).link_overhead(54);
+        assert_eq!((v4.l3, v4.l2), (26, 8));
+        let v6 = Framing::new(true).link_overhead(74);
+        assert_eq!((v6.l3, v6.l2), (26, 8));
+    }
+
+    #[test]
+    fn framing_pps() {
+        let framing = Framing::new(false);
+        let fixed = PacketSizes::Fixed(1472);
+        assert_eq!(framing.pps_of_sizes(Bitrate(12_000_000), &fixed), 1000);
+        let framing = framing.with_overhead(500);
+        assert_eq!(framing.pps_of_sizes(Bitrate(12_000_000), &fixed), 750);
+
+        // 1000 datagrams of 972 and 1972 bytes take 24 Mbit at L3
+        let sizes = "hist:972=1,1972=1".parse().unwrap();
+        let framing = Framing::new(false);
+        assert_eq!(framing.pps_of_sizes(Bitrate(24_000_000), &sizes), 2000);
+        let mut sent = BTreeMap::new();
+        sent.insert(972, 1000);
+        sent.insert(1972, 1000);
+        let rates = framing.rates_of_sizes(&sent, 1.0);
+        assert_eq!(rates.pps, 2000.0);
+        assert_eq!(rates.l3, Bitrate(24_000_000));
+    }
+}
@@ -2,14 +2,53 @@ extern crate serde;
 extern crate serde_json;
 
 use analyze::sequence::SequenceReport;
+use flow::FlowStats;
 use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tcp::TcpReport;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ControlMessage {
     RequestFlow,
     ExpectFlow(u16),
+    /// the flow is served on the server's shared UDP port, given first,
+    /// and told apart from other flows by the id, given second
+    ExpectSharedFlow(u16, u16),
     TerminateFlow(u16),
+    /// report the epoch of the flow with a throughput series at the
+    /// interval
+    TerminateEpoch(u16, u32, Duration),
     Report(SequenceReport),
+    /// send datagrams to the address from a port the client never
+    /// contacted, to tell how its NAT filters
+    RequestProbe(SocketAddr),
+    ProbeSent,
+    /// turns the connection into a bulk TCP transfer to compare with the
+    /// UDP flows, which the server reports once the client closed its
+    /// side
+    RequestTcpFlow,
+    ExpectTcpFlow,
+    TcpReport(TcpReport),
+    /// flow from the server to the client with rate, datagram length,
+    /// duration and TOS byte
+    RequestDownstream(u32, usize, Duration, u8),
+    ExpectDownstream(u16),
+    TerminateDownstream(u16),
+    DownstreamSent(FlowStats),
+    /// port that sends datagrams back to where they came from
+    RequestEcho,
+    ExpectEcho(u16),
+    TerminateEcho(u16),
+    /// number of echoed datagrams
+    EchoDone(u64),
+    /// the client's time, which the server answers with the times it
+    /// received the request and sent the reply, all in ns since the unix
+    /// epoch
+    TimeRequest(u64),
+    TimeReply(u64, u64, u64),
+    /// the server does not serve the request, for the reason given
+    Refused(String),
 }
 
 pub trait ControlStream {
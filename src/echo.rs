@@ -0,0 +1,187 @@
+extern crate serde_json;
+
+use analyze::arrival::as_ns;
+use analyze::clock::Exchange;
+use analyze::delay::RttReport;
+use nat::{is_timeout, unmap};
+use receiver::since_epoch;
+use std::io;
+use std::net::{IpAddr, UdpSocket};
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::{Duration, Instant};
+
+/// Interval of the round trip probes.
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(20);
+/// Time to wait for the echo of the last probe.
+const ECHO_WAIT: Duration = Duration::from_millis(500);
+/// Read timeout of the echo socket, to check whether to stop.
+const ECHO_POLL: Duration = Duration::from_millis(100);
+/// Echoed datagrams are never larger than this.
+const ECHO_LEN: usize = 2000;
+/// Length of the probes, which leaves room for the server's timestamps.
+const PROBE_LEN: usize = 128;
+
+/// Payload of a round trip probe. All times are in ns since the unix
+/// epoch.
+#[derive(Serialize, Deserialize, Debug)]
+struct Probe {
+    seq: u32,
+    /// sent by the client
+    sent_ns: u64,
+    /// received by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rx_ns: Option<u64>,
+    /// sent back by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tx_ns: Option<u64>,
+}
+
+/// Send datagrams on `sk` from the host `client` back to where they came
+/// from until `stop` fires or is dropped. Returns how many were echoed.
+///
+/// Probes get stamped with their time of arrival and departure. Other
+/// datagrams go back as they are. Datagrams from other hosts are dropped,
+/// lest the echo reflect to them.
+pub fn echo(
+    sk: &UdpSocket,
+    client: IpAddr,
+    stop: &mpsc::Receiver<()>,
+) -> io::Result<u64> {
+    sk.set_read_timeout(Some(ECHO_POLL))?;
+    let mut buf = [0; ECHO_LEN];
+    let mut cnt = 0;
+    loop {
+        match stop.try_recv() {
+            Err(TryRecvError::Empty) => (),
+            _ => return Ok(cnt),
+        }
+        let (len, from) = match sk.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if is_timeout(e) => continue,
+            Err(e) => return Err(e),
+        };
+        if unmap(from).ip() != client {
+            continue;
+        }
+        let rx_ns = as_ns(since_epoch());
+        match serde_json::from_slice::<Probe>(&buf[..len]) {
+            Ok(mut probe) => {
+                probe.rx_ns = Some(rx_ns);
+                probe.tx_ns = Some(as_ns(since_epoch()));
+                sk.send_to(&probe.to_vec(len), from)?
+            }
+            Err(_) => sk.send_to(&buf[..len], from)?,
+        };
+        cnt += 1;
+    }
+}
+
+impl Probe {
+    /// Serialize, padded to `len` bytes if shorter.
+    fn to_vec(&self, len: usize) -> Vec<u8> {
+        let mut buf = serde_json::to_vec(self).expect("serialize probe");
+        if buf.len() < len {
+            buf.resize(len, b' ');
+        }
+        buf
+    }
+}
+
+/// Send a probe every `interval` for `duration` on `sk`, which is
+/// connected to an echo port, and return the round trip and one-way
+/// delays.
+pub fn probe_rtt(
+    sk: &UdpSocket,
+    interval: Duration,
+    duration: Duration,
+) -> io::Result<RttReport> {
+    let mut exchanges = vec![];
+    let start = Instant::now();
+    let mut next = start;
+    let mut seq = 0;
+    while start.elapsed() < duration {
+        let probe = Probe {
+            seq,
+            sent_ns: as_ns(since_epoch()),
+            rx_ns: None,
+            tx_ns: None,
+        };
+        sk.send(&probe.to_vec(PROBE_LEN))?;
+        seq += 1;
+        next += interval;
+        receive_until(sk, next, &mut exchanges)?;
+    }
+    receive_until(sk, Instant::now() + ECHO_WAIT, &mut exchanges)?;
+    Ok(RttReport::new(seq, &exchanges))
+}
+
+/// Collect the echoes that arrive until `until`.
+fn receive_until(
+    sk: &UdpSocket,
+    until: Instant,
+    exchanges: &mut Vec<Exchange>,
+) -> io::Result<()> {
+    let mut buf = [0; ECHO_LEN];
+    loop {
+        let now = Instant::now();
+        if now >= until {
+            return Ok(());
+        }
+        sk.set_read_timeout(Some(until - now))?;
+        let len = match sk.recv(&mut buf) {
+            Ok(len) => len,
+            Err(ref e) if is_timeout(e) => continue,
+            Err(e) => return Err(e),
+        };
+        let t4 = as_ns(since_epoch());
+        if let Ok(Probe {
+            sent_ns,
+            rx_ns: Some(t2),
+            tx_ns: Some(t3),
+            ..
+        }) = serde_json::from_slice(&buf[..len])
+        {
+            exchanges.push(Exchange {
+                t1: sent_ns,
+                t2,
+                t3,
+                t4,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nat::await_datagram;
+    use std::thread;
+    use tests::fresh_pair_of_socks;
+
+    #[test]
+    fn echo_rtt() {
+        let (sk, echo_sk) = fresh_pair_of_socks();
+        let (stop, stopped) = mpsc::channel();
+        let client = sk.local_addr().unwrap().ip();
+        let port = echo_sk.local_addr().unwrap().port();
+        let echoer = thread::spawn(move || echo(&echo_sk, client, &stopped));
+
+        let interval = Duration::from_millis(10);
+        let r = probe_rtt(&sk, interval, interval * 5)
+            .expect("probe round trip times");
+        // other hosts get no echo
+        let stranger = UdpSocket::bind("127.0.0.2:0").unwrap();
+        stranger.send_to(b"ping", (client, port)).unwrap();
+        assert!(!await_datagram(&stranger, interval * 10));
+        stop.send(()).unwrap();
+        let echoed = echoer.join().unwrap().expect("echo");
+
+        assert_eq!(r.sent, 5);
+        assert_eq!(r.received, 5);
+        assert_eq!(echoed, 5);
+        assert!(r.rtt.p99_ns < 100_000_000);
+        // same clock on both ends
+        let offset = r.offset.expect("clock offset");
+        assert!(offset.offset_ns.unsigned_abs() <= offset.uncertainty_ns);
+    }
+}
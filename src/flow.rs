@@ -1,16 +1,57 @@
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+use mmsg;
+use pacing::{Pacer, Pacing, PacingStats, SendTimes};
+use profile::{RateProfile, Schedule};
+use sizes::PacketSizes;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::net::UdpSocket;
-use std::thread::sleep;
 use std::time::{Duration, Instant};
+use tos;
+
+/// What the sender achieved during a flow.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct FlowStats {
+    /// datagrams and bytes accepted by the socket
+    pub packets: u64,
+    pub bytes: u64,
+    /// time slots that found no datagram prepared
+    pub underruns: u32,
+    /// largest delay of a datagram behind its time slot
+    pub max_lateness: Duration,
+    /// datagrams refused by the socket, e.g. with ENOBUFS
+    pub send_errors: u32,
+    pub elapsed: Duration,
+    pub pacing: PacingStats,
+    /// datagrams accepted by the socket per size
+    pub sizes: BTreeMap<usize, u64>,
+}
+
+impl FlowStats {
+    fn account(&mut self, data: &[u8]) {
+        self.packets += 1;
+        self.bytes += data.len() as u64;
+        *self.sizes.entry(data.len()).or_default() += 1;
+    }
+}
 
 pub struct Flow<F>
 where
     F: FnMut(Box<[u8]>) -> Result<Box<[u8]>, &'static str>,
 {
-    pps: u32,
-    payload_len: usize,
+    profile: RateProfile,
+    sizes: PacketSizes,
     duration: Duration,
     fill_packet: F,
     sk: UdpSocket,
+    batch: usize,
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    gso: bool,
+    pacing: Pacing,
+    tos: u8,
+    /// fill datagrams as they are sent instead of ahead
+    late_fill: bool,
+    send_times: SendTimes,
+    stats: FlowStats,
 }
 
 impl<F> Flow<F>
@@ -25,64 +66,294 @@ where
         sk: UdpSocket,
     ) -> Flow<F> {
         Flow {
-            pps,
-            payload_len,
+            profile: RateProfile::Constant(pps),
+            sizes: PacketSizes::Fixed(payload_len),
             duration,
             fill_packet,
             sk,
+            batch: 1,
+            #[cfg(all(target_os = "linux", feature = "mmsg"))]
+            gso: false,
+            pacing: Pacing::Sleep,
+            tos: 0,
+            late_fill: false,
+            send_times: SendTimes::new(),
+            stats: FlowStats::default(),
         }
     }
 
+    /// Hand datagrams to the kernel in batches of up to `batch`.
+    ///
+    /// A batch leaves when the time slot of its last datagram is due, so
+    /// the average rate is kept while the datagrams of a batch are sent
+    /// back-to-back.
+    pub fn with_batch(mut self, batch: usize) -> Flow<F> {
+        self.batch = batch.max(1);
+        self
+    }
+
+    /// Send each batch as a single UDP GSO datagram.
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    pub fn with_gso(mut self, gso: bool) -> Flow<F> {
+        self.gso = gso;
+        self
+    }
+
+    /// Vary the packet rate over the flow instead of sending at a
+    /// constant rate.
+    pub fn with_profile(mut self, profile: RateProfile) -> Flow<F> {
+        self.profile = profile;
+        self
+    }
+
+    /// Draw the datagram sizes from a distribution instead of sending
+    /// `payload_len` bytes each.
+    pub fn with_sizes(mut self, sizes: PacketSizes) -> Flow<F> {
+        self.sizes = sizes;
+        self
+    }
+
+    pub fn with_pacing(mut self, pacing: Pacing) -> Flow<F> {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Mark the datagrams with the TOS byte `tos`, i.e. DSCP and ECN.
+    pub fn with_tos(mut self, tos: u8) -> Flow<F> {
+        self.tos = tos;
+        self
+    }
+
+    /// Fill each datagram right before it is sent instead of ahead of its
+    /// time slot, for payloads that carry their send time.
+    pub fn with_late_fill(mut self, late_fill: bool) -> Flow<F> {
+        self.late_fill = late_fill;
+        self
+    }
+
     pub fn to_socket(self) -> UdpSocket {
         self.sk
     }
 
-    pub fn start_xmit(&mut self) -> u32 {
-        let gap = Duration::new(0, 1_000_000_000 / self.pps);
-        let mut underruns = 0u32;
+    /// Send the datagrams and account for them in the flow's stats.
+    #[cfg(not(all(target_os = "linux", feature = "mmsg")))]
+    fn xmit(&mut self, bufs: &[Box<[u8]>]) {
+        for data in bufs {
+            match self.sk.send(data) {
+                Ok(_) => self.stats.account(data),
+                Err(_) => self.stats.send_errors += 1,
+            }
+        }
+    }
+
+    /// Send the datagrams and account for them in the flow's stats.
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    fn xmit(&mut self, bufs: &[Box<[u8]>]) {
+        if bufs.is_empty() {
+            return;
+        }
+        if self.gso {
+            let segments = mmsg::GSO_MAX_SEGMENTS;
+            for (i, chunk) in bufs.chunks(segments).enumerate() {
+                let segment = chunk[0].len() as u16;
+                if let Err(e) = mmsg::send_segmented(&self.sk, chunk, segment)
+                {
+                    println!("disable GSO: {}", e);
+                    self.gso = false;
+                    return self.xmit(&bufs[i * segments..]);
+                }
+                for data in chunk {
+                    self.stats.account(data);
+                }
+            }
+            return;
+        }
+
+        let mut sent = 0;
+        while sent < bufs.len() {
+            match mmsg::send_batch(&self.sk, &bufs[sent..]) {
+                Ok(cnt) => {
+                    for data in &bufs[sent..sent + cnt] {
+                        self.stats.account(data);
+                    }
+                    sent += cnt;
+                }
+                Err(_) => {
+                    // skip the datagram that failed
+                    self.stats.send_errors += 1;
+                    sent += 1;
+                }
+            }
+        }
+    }
+
+    /// Hand the datagrams in `sending` to the socket and recycle them.
+    fn flush(
+        &mut self,
+        pacer: &Pacer,
+        sending: &mut Vec<Box<[u8]>>,
+        slots: &mut Vec<Instant>,
+        recycled_buffers: &mut Vec<Box<[u8]>>,
+    ) {
+        let errors = self.stats.send_errors;
+        match self.pacing {
+            #[cfg(target_os = "linux")]
+            Pacing::TxTime => {
+                for (data, slot) in sending.iter().zip(slots.iter()) {
+                    match pacer.send_at(&self.sk, data, *slot) {
+                        Ok(_) => self.stats.account(data),
+                        Err(_) => self.stats.send_errors += 1,
+                    }
+                }
+            }
+            _ => self.xmit(sending),
+        }
+
+        // refused datagrams were not sent in their slots
+        let sent = slots.len() - (self.stats.send_errors - errors) as usize;
+        let now = Instant::now();
+        for slot in slots.drain(..).take(sent) {
+            self.send_times.track(slot, now);
+        }
+        recycled_buffers.append(sending);
+    }
+
+    /// Send the flow and return what the sender achieved.
+    pub fn start_xmit(&mut self) -> FlowStats {
+        let batch = self.batch;
+
+        let mut pacer =
+            Pacer::new(self.pacing, &self.sk).expect("set up pacing");
+        let lookahead = pacer.lookahead();
+        self.send_times = SendTimes::new();
+        self.stats = FlowStats::default();
+        // the socket keeps the marking of earlier flows otherwise
+        tos::set_tos(&self.sk, self.tos).unwrap_or_else(|e| {
+            println!("can't set TOS {:#04x}: {}", self.tos, e);
+        });
+        #[cfg(all(target_os = "linux", feature = "mmsg"))]
+        {
+            if self.gso && !self.sizes.is_fixed() {
+                println!("disable GSO: mixed packet sizes");
+                self.gso = false;
+            }
+        }
 
         // self.sk.set_nonblocking(true);
+        // enough buffers to fill the slots within the lookahead
+        let peak_pps = u64::from(self.profile.peak_pps());
+        let ahead =
+            lookahead.subsec_nanos() as u64 * peak_pps / 1_000_000_000;
+        let buffers = 10.max(2 * batch).max(2 * ahead as usize);
         let mut recycled_buffers =
-            vec![vec![0; self.payload_len].into_boxed_slice(); 10];
+            vec![vec![0; self.sizes.max_len()].into_boxed_slice(); buffers];
+        // buffers of other sizes than the datagram at hand, by size
+        let mut spare_buffers: HashMap<usize, Vec<Box<[u8]>>> =
+            HashMap::new();
         let mut prepared_buffers: Vec<Box<[u8]>> = Vec::new();
+        let mut sending: Vec<Box<[u8]>> = Vec::with_capacity(batch);
+        let mut slots: Vec<Instant> = Vec::with_capacity(batch);
+        let mut sizes = self.sizes.iter();
 
-        // wait relative to sleep_until (as opposed to now()) to
+        // slots are relative to the start (as opposed to now()) to
         // compensate for jitter.
+        let profile = self.profile.clone();
         let started_at = Instant::now();
-        let mut sleep_until = started_at + gap;
+        let mut schedule = Schedule::new(&profile, started_at, self.duration);
+        let mut upcoming: VecDeque<Instant> = VecDeque::new();
+        let mut scheduled = 0;
 
         while self.duration > Instant::now().duration_since(started_at) {
+            while upcoming.len() < batch {
+                match schedule.next() {
+                    Some(slot) => upcoming.push_back(slot),
+                    None => break,
+                }
+                scheduled += 1;
+            }
+            if upcoming.is_empty() {
+                break;
+            }
+            // a batch leaves when the slot of its last datagram is due
+            let deadline =
+                upcoming[upcoming.len().min(batch) - 1] - lookahead;
             let mut now = Instant::now();
-            while now < sleep_until || prepared_buffers.is_empty() {
+            while now < deadline || prepared_buffers.is_empty() {
                 if !recycled_buffers.is_empty() {
                     let mut data = recycled_buffers.pop().unwrap();
-                    data = (self.fill_packet)(data).expect("attach payload");
+                    let len = sizes.next().unwrap_or(data.len());
+                    if data.len() != len {
+                        let other = spare_buffers
+                            .entry(len)
+                            .or_default()
+                            .pop()
+                            .unwrap_or_else(|| {
+                                vec![0; len].into_boxed_slice()
+                            });
+                        spare_buffers
+                            .entry(data.len())
+                            .or_default()
+                            .push(data);
+                        data = other;
+                    }
+                    if !self.late_fill {
+                        data =
+                            (self.fill_packet)(data).expect("attach payload");
+                    }
                     prepared_buffers.insert(0, data);
                 } else {
-                    sleep(sleep_until.duration_since(now));
+                    pacer.wait_until(deadline);
                 }
                 now = Instant::now();
             }
 
-            // if sleep_until + gap < now {
-            //    println!("missed a time slot by {:?}", now - sleep_until);
-            // }
-
-            while sleep_until < now {
+            while let Some(&slot) = upcoming.front() {
+                if slot >= now + lookahead {
+                    break;
+                }
                 if prepared_buffers.is_empty() {
                     // println!("buffer underrun");
-                    underruns += 1;
+                    self.stats.underruns += 1;
                     break;
                 }
-                let data = prepared_buffers.pop().unwrap();
-                self.sk.send(&data).expect("transmit datagram");
-                recycled_buffers.insert(0, data);
+                let mut data = prepared_buffers.pop().unwrap();
+                if self.late_fill {
+                    data = (self.fill_packet)(data).expect("attach payload");
+                }
+                sending.push(data);
+                slots.push(slot);
+                upcoming.pop_front();
+                if sending.len() == batch {
+                    self.flush(
+                        &pacer,
+                        &mut sending,
+                        &mut slots,
+                        &mut recycled_buffers,
+                    );
+                }
 
-                underruns = 0;
-                sleep_until += gap;
+                if upcoming.is_empty() {
+                    upcoming.extend(schedule.next());
+                    scheduled += upcoming.len();
+                }
             }
+            self.flush(
+                &pacer,
+                &mut sending,
+                &mut slots,
+                &mut recycled_buffers,
+            );
         }
-        return underruns;
+        // slots that were not even reached count as requested, too
+        scheduled += schedule.count();
+        // the rate is measured over the whole flow, even if it ends quietly
+        pacer.wait_until(started_at + self.duration);
+
+        self.stats.elapsed = started_at.elapsed();
+        self.stats.max_lateness = self.send_times.max_lateness();
+        self.stats.pacing =
+            self.send_times.stats(self.stats.elapsed, scheduled as u64);
+        self.stats.clone()
     }
 }
 
@@ -132,4 +403,146 @@ mod tests {
         flow.start_xmit();
         assert!(sk_rcv.peek(&mut buffer).expect("peek a dgram") == size);
     }
+
+    fn count_dgrams(sk: &UdpSocket, size: usize) -> usize {
+        let mut buffer = [0; 2000];
+        let mut cnt = 0;
+        sk.set_read_timeout(Some(Duration::from_millis(100)))
+            .expect("set timeout");
+        while let Ok(len) = sk.recv(&mut buffer) {
+            assert_eq!(len, size);
+            cnt += 1;
+        }
+        cnt
+    }
+
+    #[test]
+    fn flow_xmit_batch() {
+        let (sk, sk_rcv) = fresh_pair_of_socks();
+
+        let mut flow =
+            Flow::from_socket(1000, 100, Duration::from_millis(100), Ok, sk)
+                .with_batch(8);
+        flow.start_xmit();
+        let cnt = count_dgrams(&sk_rcv, 100);
+        assert!((90..=108).contains(&cnt), "received {}", cnt);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    #[test]
+    fn flow_xmit_gso() {
+        let (sk, sk_rcv) = fresh_pair_of_socks();
+
+        let mut flow =
+            Flow::from_socket(1000, 100, Duration::from_millis(100), Ok, sk)
+                .with_batch(8)
+                .with_gso(true);
+        flow.start_xmit();
+        let cnt = count_dgrams(&sk_rcv, 100);
+        assert!((90..=108).contains(&cnt), "received {}", cnt);
+    }
+
+    #[test]
+    fn flow_stats() {
+        let (sk, sk_rcv) = fresh_pair_of_socks();
+
+        let mut flow =
+            Flow::from_socket(1000, 100, Duration::from_millis(100), Ok, sk);
+        let stats = flow.start_xmit();
+        assert_eq!(stats.packets, count_dgrams(&sk_rcv, 100) as u64);
+        assert_eq!(stats.bytes, stats.packets * 100);
+        assert_eq!(stats.send_errors, 0);
+        assert!(stats.elapsed >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn flow_late_fill() {
+        let (sk, sk_rcv) = fresh_pair_of_socks();
+
+        // only datagrams about to be sent get filled
+        let mut filled = 0;
+        let stats = Flow::from_socket(
+            1000,
+            100,
+            Duration::from_millis(100),
+            |buf| {
+                filled += 1;
+                Ok(buf)
+            },
+            sk,
+        )
+        .with_late_fill(true)
+        .start_xmit();
+        assert_eq!(filled, stats.packets);
+        assert_eq!(stats.packets, count_dgrams(&sk_rcv, 100) as u64);
+    }
+
+    #[test]
+    fn flow_send_errors() {
+        let (sk, sk_rcv) = fresh_pair_of_socks();
+        // sends fail with ECONNREFUSED once the port is closed
+        drop(sk_rcv);
+
+        let mut flow =
+            Flow::from_socket(1000, 100, Duration::from_millis(100), Ok, sk);
+        let stats = flow.start_xmit();
+        let paced = stats.pacing.achieved_pps * stats.elapsed.as_secs_f64();
+        assert_eq!(paced.round() as u64, stats.packets, "{:?}", stats);
+        if cfg!(target_os = "linux") {
+            assert!(stats.send_errors > 0);
+            assert!(stats.pacing.accuracy < 0.9);
+        }
+    }
+
+    #[test]
+    fn flow_pacing_modes() {
+        let mut modes = vec![Pacing::Sleep, Pacing::Hybrid];
+        #[cfg(target_os = "linux")]
+        modes.extend_from_slice(&[Pacing::TimerFd, Pacing::TxTime]);
+
+        for pacing in modes {
+            let (sk, sk_rcv) = fresh_pair_of_socks();
+            let mut flow = Flow::from_socket(
+                2000,
+                100,
+                Duration::from_millis(100),
+                Ok,
+                sk,
+            )
+            .with_pacing(pacing);
+            let stats = flow.start_xmit();
+            assert!(
+                (0.9..1.1).contains(&stats.pacing.accuracy),
+                "{:?}: {:?}",
+                pacing,
+                stats
+            );
+            let cnt = count_dgrams(&sk_rcv, 100);
+            assert!((180..=210).contains(&cnt), "{:?}: {}", pacing, cnt);
+        }
+    }
+
+    #[test]
+    fn flow_xmit_sizes() {
+        let (sk, sk_rcv) = fresh_pair_of_socks();
+
+        let sizes = "hist:100=3,300=1".parse().unwrap();
+        let mut flow =
+            Flow::from_socket(1000, 100, Duration::from_millis(100), Ok, sk)
+                .with_sizes(sizes);
+        let stats = flow.start_xmit();
+        assert_eq!(stats.sizes.len(), 2);
+        assert_eq!(stats.sizes[&100], 3 * stats.sizes[&300]);
+
+        let mut buffer = [0; 2000];
+        sk_rcv
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .expect("set timeout");
+        let mut lens = vec![];
+        while let Ok(len) = sk_rcv.recv(&mut buffer) {
+            lens.push(len);
+        }
+        assert_eq!(lens.len() as u64, stats.packets);
+        assert_eq!(&lens[..4], [100, 100, 300, 100]);
+    }
 }
@@ -8,19 +8,75 @@ extern crate serde_json;
 extern crate structopt;
 
 mod analyze;
+mod bitrate;
 mod control;
+mod echo;
 mod flow;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+mod mmsg;
+mod monitor;
+mod nat;
+mod pacing;
+mod pmtu;
+mod profile;
+mod receiver;
+mod sizes;
+#[cfg(target_os = "linux")]
+mod sockaddr;
+mod tcp;
+mod tos;
 
-use analyze::sequence::{ReSequencer, SequenceReport};
+use analyze::arrival::as_ns;
+use analyze::delay::RttReport;
+use analyze::mtu::discover_pmtu;
+use analyze::parallel::Flows;
+use analyze::sequence::{SequenceReport, Sequencer};
+use analyze::series::{ThroughputSeries, SERIES_INTERVAL};
+use analyze::tracker::FlowTracker;
 use analyze::SequencedPayload;
+use analyze::Session;
+use bitrate::{Bitrate, Framing, LinkOverhead};
 use control::{ControlMessage, ControlStream};
+use flow::{Flow, FlowStats};
+use monitor::{Mapping, Store};
+use nat::Punch;
+use pacing::Pacing;
+use profile::RateProfile;
+use receiver::{since_epoch, Receiver};
+use sizes::PacketSizes;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::net::ToSocketAddrs;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use tos::{Dscp, Ecn};
+
+/// Datagrams read at once by the server
+const RECV_BATCH: usize = 32;
+/// Payload length of flows that are not part of the rate search
+const PROBE_LEN: usize = 1200;
+/// Time a new flow waits for the client to punch a hole
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(2);
+/// Datagrams sent to probe how the client's NAT filters
+const FILTER_PROBES: usize = 3;
+/// Rate of the flow that fills the bottleneck queue, relative to the
+/// maximum rate
+const SATURATION: f64 = 1.1;
+/// Candidate shaper rate relative to the maximum rate, which keeps the
+/// bottleneck queue from building up
+const SHAPER_SHARE: f64 = 0.9;
+/// Time a flow on the shared port has to be quiet before its epoch is
+/// reported, like the read timeout of a flow on a port of its own
+const FLOW_LINGER: Duration = Duration::from_millis(1000);
+/// Read timeout of the shared port, to check for control messages
+const SHARED_POLL: Duration = Duration::from_millis(100);
 
 /// qosmap options
 #[derive(StructOpt, Debug)]
@@ -34,12 +90,118 @@ struct Opt {
     /// server port
     #[structopt(short = "p", long = "port", default_value = "4801")]
     port: u16,
+    /// serve all flows on this UDP port instead of a random port per flow
+    #[structopt(long = "udp-port")]
+    udp_port: Option<u16>,
+    /// reach the server over IPv4
+    #[structopt(short = "4", long = "ipv4", conflicts_with = "ipv6")]
+    ipv4: bool,
+    /// reach the server over IPv6
+    #[structopt(short = "6", long = "ipv6")]
+    ipv6: bool,
+    /// measure over IPv4 and over IPv6 and compare the per packet overhead
+    #[structopt(
+        long = "dual-stack",
+        raw(conflicts_with_all = r#"&["ipv4", "ipv6"]"#)
+    )]
+    dual_stack: bool,
     /// packet rate in packets per second
     #[structopt(short = "r", long = "rate", default_value = "1000")]
     rate: u32,
+    /// run a single flow at a bit rate instead of the rate search, e.g.
+    /// 50M; the packet rate follows from the datagram sizes
+    #[structopt(long = "bitrate", conflicts_with = "profile")]
+    bitrate: Option<Bitrate>,
+    /// per packet overhead in bytes on top of the IP packet that the bit
+    /// rate accounts for
+    #[structopt(long = "overhead", default_value = "0")]
+    overhead: usize,
     /// duration of the test in seconds
     #[structopt(short = "d", long = "duration", default_value = "1")]
     duration: u64,
+    /// datagrams handed to the kernel at once
+    #[structopt(short = "b", long = "batch", default_value = "1")]
+    batch: usize,
+    /// send batches as UDP GSO datagrams
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    #[structopt(long = "gso")]
+    gso: bool,
+    /// pacing mode: sleep, hybrid, timerfd or txtime
+    #[structopt(long = "pacing", default_value = "sleep")]
+    pacing: Pacing,
+    /// run a single flow following a rate profile instead of the rate
+    /// search: <pps>, ramp:<pps>:<pps>, steps:<secs>=<pps>,...,
+    /// burst:<pps>:<on ms>:<off ms> or file:<path>
+    #[structopt(long = "profile")]
+    profile: Option<RateProfile>,
+    /// run a single flow with mixed datagram sizes instead of the rate
+    /// search: <len>, imix, uniform:<len>:<len>, hist:<len>=<weight>,...
+    /// or file:<path>
+    #[structopt(long = "sizes")]
+    sizes: Option<PacketSizes>,
+    /// parallel flows of a single flow run, from different source ports:
+    /// <n> or <n>:<dscp>,... to mark them with classes; the rate applies
+    /// to each flow
+    #[structopt(long = "flows", default_value = "1")]
+    flows: Flows,
+    /// DSCP classes to measure one after another, as numbers or names
+    /// like ef, af41 or cs1
+    #[structopt(long = "dscp", raw(use_delimiter = "true"))]
+    dscp: Vec<Dscp>,
+    /// mark datagrams as ECN capable with ect0 or ect1; congestion
+    /// experienced marks then count as loss
+    #[structopt(long = "ecn", default_value = "off")]
+    ecn: Ecn,
+    /// seed the rate search with a packet train estimate
+    #[structopt(short = "e", long = "estimate")]
+    estimate: bool,
+    /// skip the path MTU discovery and do not cap datagram sizes
+    #[structopt(long = "no-pmtud")]
+    no_pmtud: bool,
+    /// tell how a NAT between client and server maps and filters UDP
+    #[structopt(long = "nat-type")]
+    nat_type: bool,
+    /// find the maximum rate upstream and downstream, then load both
+    /// directions at once and compare rate and delay
+    #[structopt(long = "bidir")]
+    bidir: bool,
+    /// measure the round trip time idle, while saturating the path
+    /// upstream and at a candidate shaper rate
+    #[structopt(long = "bufferbloat", conflicts_with = "bidir")]
+    bufferbloat: bool,
+    /// also run a bulk TCP transfer and compare its goodput with the UDP
+    /// rate; the only test if UDP does not get through
+    #[structopt(long = "tcp")]
+    tcp: bool,
+    /// print the receiver's throughput of a single flow run per interval
+    /// of this many ms
+    #[structopt(long = "series")]
+    series: Option<u64>,
+    /// run the rate search every this many seconds, give or take 10%,
+    /// until stopped
+    #[structopt(
+        long = "monitor",
+        raw(conflicts_with_all = r#"&[
+            "dual_stack", "bidir", "bufferbloat",
+            "profile", "sizes", "bitrate", "flows",
+        ]"#)
+    )]
+    monitor: Option<u64>,
+    /// file the monitor appends the results of each run to, one JSON
+    /// object per line
+    #[structopt(long = "store", default_value = "qosmap.jsonl")]
+    store: PathBuf,
+    /// shell command to run when the results of a monitoring run moved
+    /// beyond a threshold; it gets the changes in QOSMAP_CHANGES and the
+    /// results in QOSMAP_RECORD
+    #[structopt(long = "alert")]
+    alert: Option<String>,
+    /// change of the goodput in percent that raises an alert
+    #[structopt(long = "rate-threshold", default_value = "10")]
+    rate_threshold: f64,
+    /// change of the overhead in bytes that raises an alert
+    #[structopt(long = "overhead-threshold", default_value = "4")]
+    overhead_threshold: i64,
 }
 
 fn main() {
@@ -58,93 +220,960 @@ fn mainymain(args: Vec<String>) {
     if opt.server {
         let tcp_listener = TcpListener::bind((host, opt.port))
             .expect("bind to control port");
+        let shared = opt.udp_port.map(|port| {
+            SharedPort::bind(host, port).expect("bind to shared UDP port")
+        });
         for stream in tcp_listener.incoming() {
             let mut ctrl_sk = stream.unwrap();
+            let shared = shared.clone();
             thread::spawn(move || {
                 let peer: String =
                     format!("{:?}", ctrl_sk.peer_addr().unwrap());
-                serve_client(ctrl_sk).unwrap_or_else(|e| {
+                serve_client(ctrl_sk, shared).unwrap_or_else(|e| {
                     println!("Error for connection from {}: {}", peer, e);
                 });
             });
         }
     } else {
-        use analyze::find_max_pps;
         // client
-        let mut sock_addrs =
-            (host, opt.port).to_socket_addrs().expect("resolve host");
-        let sock_addr = sock_addrs.nth(0).unwrap();
-        let len: (u32, u32) = (800, 1200);
-        let pps = (
-            find_max_pps(sock_addr, len.0 as usize).expect("detect max rate"),
-            find_max_pps(sock_addr, len.1 as usize).expect("detect max rate"),
+        if let Some(secs) = opt.monitor {
+            monitor(host, &opt, Duration::from_secs(secs));
+        }
+        let families = if opt.dual_stack {
+            vec![Some(false), Some(true)]
+        } else if opt.ipv4 || opt.ipv6 {
+            vec![Some(opt.ipv6)]
+        } else {
+            vec![None]
+        };
+        let mappings: Vec<Vec<Mapping>> = families
+            .into_iter()
+            .map(|ipv6| match resolve(host, opt.port, ipv6) {
+                Ok(sock_addr) => {
+                    if opt.dual_stack {
+                        println!("measure via {}", sock_addr);
+                    }
+                    measure(sock_addr, &opt).expect("measure")
+                }
+                Err(e) if opt.dual_stack => {
+                    println!("skip: {}", e);
+                    vec![]
+                }
+                Err(e) => panic!("resolve host: {}", e),
+            })
+            .collect();
+        if opt.dual_stack {
+            compare_families(&mappings[0], &mappings[1]);
+        }
+    }
+}
+
+/// Run the rate search every `interval`, give or take some jitter, until
+/// stopped. The results of each run are appended to the store and raise
+/// an alert if they moved too far from the previous ones.
+fn monitor(host: &str, opt: &Opt, interval: Duration) -> ! {
+    use monitor::jittered;
+
+    let store = Store::new(opt.store.clone());
+    loop {
+        let start = Instant::now();
+        if let Err(e) = monitor_run(host, opt, &store) {
+            println!("monitoring run failed: {}", e);
+        }
+        // the sub-second part of the clock is random enough to spread runs
+        let random = f64::from(since_epoch().subsec_nanos()) / 1e9;
+        let next = jittered(interval, random);
+        if let Some(wait) = next.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Measure once, store the results and alert on changes.
+fn monitor_run(host: &str, opt: &Opt, store: &Store) -> Result<(), String> {
+    use monitor::{alert, changes, Record, Thresholds};
+
+    let ipv6 = if opt.ipv4 || opt.ipv6 {
+        Some(opt.ipv6)
+    } else {
+        None
+    };
+    let sock_addr = resolve(host, opt.port, ipv6)?;
+    let time = since_epoch().as_secs();
+    let mappings = measure(sock_addr, opt)?;
+    let record = Record {
+        time,
+        server: sock_addr.to_string(),
+        mappings,
+    };
+
+    let prev = store.last()?;
+    store.append(&record)?;
+    let thresholds = Thresholds {
+        rate: opt.rate_threshold / 100.0,
+        overhead: opt.overhead_threshold,
+    };
+    let changed = match prev {
+        Some(prev) => changes(&prev, &record, thresholds),
+        None => vec![],
+    };
+    for change in &changed {
+        println!("{}", change);
+    }
+    match opt.alert {
+        Some(ref command) if !changed.is_empty() => {
+            alert(command, &changed, &record)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Resolve `host` to an address of the given family, any if `None`.
+fn resolve(
+    host: &str,
+    port: u16,
+    ipv6: Option<bool>,
+) -> Result<SocketAddr, String> {
+    let mut sock_addrs = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("{}: {}", host, e))?;
+    sock_addrs
+        .find(|addr| ipv6.is_none() || ipv6 == Some(addr.is_ipv6()))
+        .ok_or_else(|| match ipv6 {
+            Some(true) => format!("{} has no IPv6 address", host),
+            _ => format!("{} has no IPv4 address", host),
+        })
+}
+
+/// Run the measurement for each DSCP class against `sock_addr`. Returns
+/// the overheads and rates found by the rate search.
+fn measure(sock_addr: SocketAddr, opt: &Opt) -> Result<Vec<Mapping>, String> {
+    let mut session = Session::connect(sock_addr)
+        .map_err(|e| format!("open control connection: {}", e))?;
+    session.set_batch(opt.batch);
+    session.set_pacing(opt.pacing);
+    session.set_overhead(opt.overhead);
+    if let Some(ms) = opt.series {
+        session.set_series_interval(Duration::from_millis(ms));
+    }
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    session.set_gso(opt.gso);
+    print_nat(&mut session, opt);
+    if opt.tcp {
+        if let Ok((_, None)) = session.addresses() {
+            println!("UDP does not get through, measure TCP only");
+            probe_tcp(&mut session, opt, None);
+            session.close()?;
+            return Ok(vec![]);
+        }
+    }
+    let pmtu_len = if opt.no_pmtud {
+        None
+    } else {
+        discover_pmtu(&mut session)
+            .map_err(|e| println!("no path MTU: {}", e))
+            .ok()
+    };
+    let max_len = pmtu_len.unwrap_or(sizes::MAX_LEN);
+    let mtu = pmtu_len.map(|len| session.framing().l3_len(len));
+
+    let classes = if opt.dscp.is_empty() {
+        vec![Dscp(0)]
+    } else {
+        opt.dscp.clone()
+    };
+    let mut mappings = vec![];
+    let mut udp_goodput = None;
+    for dscp in classes {
+        if !opt.dscp.is_empty() {
+            println!("measure DSCP class {}", dscp);
+        }
+        session.set_tos(dscp.tos() | opt.ecn.tos());
+        if opt.profile.is_some()
+            || opt.sizes.is_some()
+            || opt.bitrate.is_some()
+            || opt.flows.len() > 1
+        {
+            probe(&mut session, opt, max_len);
+        } else if opt.bidir {
+            map_bidirectional(&mut session, opt, max_len);
+        } else if opt.bufferbloat {
+            map_latency(&mut session, opt, max_len);
+        } else {
+            let (overhead, goodput) = map_rate(&mut session, opt, max_len)?;
+            print_link_types(overhead.l3, mtu);
+            mappings.push(Mapping {
+                dscp,
+                overhead,
+                goodput,
+            });
+            // TCP runs unmarked
+            if dscp == Dscp(0) {
+                udp_goodput = Some(goodput);
+            }
+        }
+    }
+    if opt.tcp {
+        probe_tcp(&mut session, opt, udp_goodput);
+    }
+    session.close()?;
+    Ok(mappings)
+}
+
+/// Print whether the client is behind a NAT and, if asked for, its type.
+fn print_nat(session: &mut Session, opt: &Opt) {
+    match session.addresses() {
+        Ok((local, Some(mapped))) if mapped != local => {
+            println!("behind NAT, {} is seen as {}", local, mapped)
+        }
+        Ok((local, Some(_))) => println!("no NAT, {} is seen as is", local),
+        Ok((_, None)) => println!("no answer to hole punching"),
+        Err(e) => println!("{}", e),
+    }
+    if opt.nat_type {
+        match session.detect_nat() {
+            Ok(nat_type) => println!("NAT type: {}", nat_type),
+            Err(e) => println!("no NAT type: {}", e),
+        }
+    }
+}
+
+/// Print the link types that match the overhead on top of the IP packet
+/// and the path MTU.
+fn print_link_types(overhead: i64, mtu: Option<usize>) {
+    use analyze::link::{fingerprint, MIN_CONFIDENCE};
+
+    let links = fingerprint(overhead, mtu);
+    let likely: Vec<_> = links
+        .iter()
+        .filter(|link| link.1 >= MIN_CONFIDENCE)
+        .collect();
+    if likely.is_empty() {
+        println!("no known link type matches");
+    }
+    for (link, confidence) in likely {
+        println!(
+            "link type {}: {:.0}% (overhead {}, MTU {})",
+            link.name,
+            confidence * 100.0,
+            link.overhead,
+            link.mtu
+        );
+    }
+}
+
+/// Print how the overheads measured over IPv4 and IPv6 relate.
+fn compare_families(v4: &[Mapping], v6: &[Mapping]) {
+    use analyze::family::{compare, FamilyPath};
+
+    if v4.is_empty() || v6.is_empty() {
+        println!("no overheads to compare");
+    }
+    for (v4, v6) in v4.iter().zip(v6) {
+        let (v4, v6) = (v4.overhead, v6.overhead);
+        println!(
+            "overhead on top of the IP packet: IPv4 {}, IPv6 {} ({:+})",
+            v4.l3,
+            v6.l3,
+            v6.l3 - v4.l3
+        );
+        match compare(v4.l3, v6.l3) {
+            FamilyPath::Native => {
+                println!("both families see the same overhead")
+            }
+            FamilyPath::Ipv6Tunneled(extra) => println!(
+                "IPv6 carries {} extra bytes, likely tunneled over IPv4",
+                extra
+            ),
+            FamilyPath::Ipv4Tunneled(extra) => println!(
+                "IPv4 carries {} extra bytes, likely tunneled over IPv6",
+                extra
+            ),
+        }
+    }
+}
+
+/// Search the maximum rate for two packet sizes up to `max_len` and derive
+/// the overhead. Also returns the goodput of the longer datagrams.
+fn map_rate(
+    session: &mut Session,
+    opt: &Opt,
+    max_len: usize,
+) -> Result<(LinkOverhead, Bitrate), String> {
+    use analyze::train::estimate_capacity;
+    use analyze::{find_max_pps, Direction};
+
+    let estimate = if opt.estimate {
+        estimate_capacity(session)
+            .map_err(|e| println!("no estimate: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    let start_pps =
+        |len: u32| estimate.as_ref().map_or(1000, |e| e.pps(len as usize));
+    let long = 1200.min(max_len as u32);
+    let len: (u32, u32) = (long * 2 / 3, long);
+    let pps = (
+        find_max_pps(
+            session,
+            Direction::Up,
+            len.0 as usize,
+            start_pps(len.0),
+        )
+        .map_err(|e| format!("detect max rate: {}", e))?,
+        find_max_pps(
+            session,
+            Direction::Up,
+            len.1 as usize,
+            start_pps(len.1),
+        )
+        .map_err(|e| format!("detect max rate: {}", e))?,
+    );
+
+    println!("pps {:?}", pps);
+    if pps.0 == pps.1 {
+        return Err(format!("same rate of {} pps for both lengths", pps.0));
+    }
+    let net_rate: (i64, i64) =
+        ((pps.0 * len.0).into(), (pps.1 * len.1).into());
+    let overhead = (net_rate.1 - net_rate.0) / (pps.0 - pps.1) as i64;
+    let framing = session.framing();
+    let link_overhead = framing.link_overhead(overhead);
+    println!("overhead {}", link_overhead);
+    let gross_rate = (
+        pps.0 as i64 * (len.0 as i64 + overhead),
+        pps.1 as i64 * (len.1 as i64 + overhead),
+    );
+    println!("gross_rate {:?}", gross_rate.0.min(gross_rate.1));
+    println!(
+        "{} bytes: {}",
+        len.0,
+        framing.rates(pps.0.into(), len.0 as usize)
+    );
+    println!(
+        "{} bytes: {}",
+        len.1,
+        framing.rates(pps.1.into(), len.1 as usize)
+    );
+    Ok((link_overhead, Bitrate(u64::from(pps.1 * len.1) * 8)))
+}
+
+/// Search the maximum rate upstream and downstream for datagrams up to
+/// `max_len`, then run both directions at once at these rates and print
+/// how each fares compared to running alone.
+fn map_bidirectional(session: &mut Session, opt: &Opt, max_len: usize) {
+    use analyze::{find_max_pps, Direction};
+
+    if session.shared() {
+        return println!(
+            "both directions need a downstream flow, which a server with a \
+             shared UDP port refuses"
+        );
+    }
+    let len = PROBE_LEN.min(max_len);
+    let up = find_max_pps(session, Direction::Up, len, 1000)
+        .expect("detect max rate upstream");
+    let down = find_max_pps(session, Direction::Down, len, 1000)
+        .expect("detect max rate downstream");
+
+    let duration = Duration::from_secs(opt.duration);
+    let offset = session.sync_clock().expect("synchronize clocks");
+    println!("{}", offset);
+    session.set_timestamps(true);
+    let alone = (
+        session.run_flow(up, len, duration).expect("run upstream"),
+        session
+            .run_downstream(down, len, duration)
+            .expect("run downstream"),
+    );
+    let both = session
+        .run_bidirectional(up, down, len, duration)
+        .expect("run both directions");
+    session.set_timestamps(false);
+
+    let framing = session.framing();
+    let secs = opt.duration as f64;
+    for &(direction, alone, both) in [
+        (Direction::Up, &alone.0, &both.0),
+        (Direction::Down, &alone.1, &both.1),
+    ]
+    .iter()
+    {
+        let passed = |r: &SequenceReport| f64::from(r.passed());
+        let (alone, both) = (&alone.1, &both.1);
+        println!(
+            "{} alone: {}",
+            direction,
+            framing.rates(passed(alone) / secs, len)
         );
+        println!(
+            "{} under load: {} ({:.0}% of alone)",
+            direction,
+            framing.rates(passed(both) / secs, len),
+            passed(both) / passed(alone).max(1.0) * 100.0
+        );
+        if let (Some(alone), Some(both)) = (&alone.delay, &both.delay) {
+            println!("{} alone: {}", direction, alone);
+            println!("{} under load: {}", direction, both);
+        }
+    }
+}
+
+/// Measure the round trip time idle, while a flow saturates the path
+/// upstream and while it runs at a candidate shaper rate below the
+/// maximum rate, and print how much delay the load adds.
+fn map_latency(session: &mut Session, opt: &Opt, max_len: usize) {
+    use analyze::{find_max_pps, Direction};
 
-        println!("pps {:?}", pps);
-        let net_rate: (i64, i64) =
-            ((pps.0 * len.0).into(), (pps.1 * len.1).into());
-        let overhead = (net_rate.1 - net_rate.0) / (pps.0 - pps.1) as i64;
-        println!("overhead {}", overhead);
-        let gross_rate = (
-            pps.0 as i64 * (len.0 as i64 + overhead),
-            pps.1 as i64 * (len.1 as i64 + overhead),
+    if session.shared() {
+        return println!(
+            "round trip probes need an echo port, which a server with a \
+             shared UDP port refuses"
+        );
+    }
+    let duration = Duration::from_secs(opt.duration);
+    let idle = session.measure_rtt(duration).expect("measure idle rtt");
+    print_rtt("idle", &idle);
+
+    let len = PROBE_LEN.min(max_len);
+    let max = find_max_pps(session, Direction::Up, len, 1000)
+        .expect("detect max rate");
+    let framing = session.framing();
+    let loads = [("saturated", SATURATION), ("shaped", SHAPER_SHARE)];
+    for &(name, share) in loads.iter() {
+        let pps = (f64::from(max) * share) as u32;
+        let (_, rtt) = session
+            .run_flow_with_rtt(pps, len, duration)
+            .expect("measure rtt under load");
+        println!("{} at {}", name, framing.rates(pps.into(), len));
+        print_rtt(name, &rtt);
+        let ms = |a: u64, b: u64| (a as f64 - b as f64) / 1e6;
+        println!(
+            "{} adds {:.2} ms at p50 and {:.2} ms at p90",
+            name,
+            ms(rtt.rtt.p50_ns, idle.rtt.p50_ns),
+            ms(rtt.rtt.p90_ns, idle.rtt.p90_ns)
         );
-        println!("gross_rate {:?}", gross_rate.0.min(gross_rate.1));
     }
 }
 
-fn receive_flow<T>(sk: UdpSocket, mut abort_cond: T) -> SequenceReport
-where
-    T: FnMut() -> bool + Sized,
-{
-    let mut reseq = ReSequencer::new();
+/// Print round trip and, with a clock offset, one-way delays.
+fn print_rtt(name: &str, r: &RttReport) {
+    println!("{}: {}", name, r);
+    if let Some(offset) = r.offset {
+        println!("{}: up {}", name, r.up);
+        println!("{}: down {}", name, r.down);
+        println!("{}: {}", name, offset);
+    }
+}
 
-    let mut buffer = [0; 2000];
+/// Run a bulk TCP transfer and print its goodput and how TCP fared. A
+/// `udp_goodput` far below the TCP one points to UDP being throttled.
+fn probe_tcp(session: &mut Session, opt: &Opt, udp_goodput: Option<Bitrate>) {
+    use analyze::transport::udp_throttled;
+
+    let (stats, r) = match session.run_tcp(Duration::from_secs(opt.duration))
+    {
+        Ok(result) => result,
+        Err(e) => return println!("no TCP transfer: {}", e),
+    };
+    println!(
+        "TCP: received {} of {} bytes in {:.2} s, goodput {}",
+        r.bytes,
+        stats.bytes,
+        r.secs,
+        r.goodput()
+    );
+    if let Some((min, mean, max)) = stats.rtt() {
+        println!(
+            "TCP rtt min {:?} avg {:?} max {:?}, cwnd up to {} bytes, {} \
+             retransmits",
+            min,
+            mean,
+            max,
+            stats.max_cwnd(),
+            stats.retransmits()
+        );
+    }
+    if let Some(udp) = udp_goodput {
+        println!("UDP goodput {}, TCP goodput {}", udp, r.goodput());
+        if let Some(share) = udp_throttled(udp, r.goodput()) {
+            println!(
+                "UDP gets {:.0}% of the TCP goodput, likely throttled",
+                share * 100.0
+            );
+        }
+    }
+}
+
+/// Run a single flow with the given rate profile and sizes and print the
+/// result. Datagrams should not exceed `max_len`.
+fn probe(session: &mut Session, opt: &Opt, max_len: usize) {
+    use analyze::arrival::as_ns;
+    use analyze::print_remarking;
+    use analyze::size::{delivery, estimate_from_sizes};
+
+    let framing = session.framing();
+    let sizes = opt
+        .sizes
+        .clone()
+        .unwrap_or_else(|| PacketSizes::Fixed(PROBE_LEN.min(max_len)));
+    if sizes.max_len() > max_len {
+        println!("datagrams above {} bytes exceed the path MTU", max_len);
+    }
+    let profile = match (opt.profile.as_ref(), opt.bitrate) {
+        (Some(profile), _) => profile.clone(),
+        (None, Some(bitrate)) => {
+            RateProfile::Constant(framing.pps_of_sizes(bitrate, &sizes))
+        }
+        (None, None) => RateProfile::Constant(opt.rate),
+    };
+    if opt.flows.len() > 1 {
+        return probe_parallel(session, opt, profile, sizes);
+    }
+    let mixed = !sizes.is_fixed();
+    let (stats, r) = session
+        .run_profile(profile, sizes, Duration::from_secs(opt.duration))
+        .expect("run profile");
+    println!("{:?}", stats);
+    let missing: u32 = r.missing.iter().map(|(a, b)| (b + 1) - a).sum();
+    println!(
+        "received {} of {} datagrams, {} missing, {} dups, {} CE marked",
+        r.cnt, stats.packets, missing, r.dups, r.ce
+    );
+    let secs = |ns: u64| ns as f64 / 1e9;
+    println!(
+        "sent {}",
+        framing.rates_of_sizes(&stats.sizes, secs(as_ns(stats.elapsed)))
+    );
+    let received =
+        r.sizes.iter().map(|s| (s.len, u64::from(s.cnt))).collect();
+    let arrival_ns = r.arrival.last_ns.saturating_sub(r.arrival.first_ns);
+    if arrival_ns > 0 {
+        println!(
+            "received {}",
+            framing.rates_of_sizes(&received, secs(arrival_ns))
+        );
+    }
+    println!("spacing suggests {} pps", r.arrival.spacing_pps());
+    print_remarking(session.tos(), &r);
+    if let (Some(_), Some(series)) = (opt.series, &r.series) {
+        print_series(framing, series);
+    }
+    if mixed {
+        for size in delivery(&stats.sizes, &r.sizes) {
+            println!(
+                "{} bytes: received {} of {} datagrams ({:.2}%)",
+                size.len,
+                size.received,
+                size.sent,
+                size.ratio() * 100.0
+            );
+        }
+        match estimate_from_sizes(&r.sizes) {
+            Some(estimate) => {
+                println!("{:?}", estimate);
+                let overhead = estimate.overhead.round() as i64;
+                println!("overhead {}", framing.link_overhead(overhead));
+            }
+            None => println!("sizes do not allow an estimate"),
+        }
+    }
+}
+
+/// Print the rates of each interval of a throughput series.
+fn print_series(framing: Framing, series: &ThroughputSeries) {
+    for (i, sample) in series.samples.iter().enumerate() {
+        println!(
+            "{:.2} s: {} datagrams, {}",
+            (i as u64 * series.interval_ns) as f64 / 1e9,
+            sample.packets,
+            framing.rates(series.pps(sample), sample.mean_len())
+        );
+    }
+}
+
+/// Run the probe as a single flow and as parallel flows to tell per-flow
+/// from aggregate shaping.
+fn probe_parallel(
+    session: &mut Session,
+    opt: &Opt,
+    profile: RateProfile,
+    sizes: PacketSizes,
+) {
+    use analyze::parallel::{classify, fairness};
+    use analyze::print_remarking;
+    use std::collections::BTreeMap;
+
+    let duration = Duration::from_secs(opt.duration);
+    let secs = opt.duration as f64;
+    let passed = |cnt: u32, dups: u32| f64::from(cnt - dups) / secs;
+
+    let (_, r) = session
+        .run_profile(profile.clone(), sizes.clone(), duration)
+        .expect("run single flow");
+    let single = passed(r.cnt, r.dups);
+    println!("single flow: {:.0} pps passed", single);
+
+    let flows = session
+        .run_parallel(&opt.flows, profile, sizes, duration)
+        .expect("run parallel flows");
+    let framing = session.framing();
+    let mut aggregate = BTreeMap::new();
+    let mut rates = vec![];
+    let mut classes: Vec<(Dscp, Vec<f64>)> = vec![];
+    for (i, ((stats, r), class)) in flows.iter().zip(&opt.flows.0).enumerate()
+    {
+        let mut received = BTreeMap::new();
+        for size in &r.sizes {
+            received.insert(size.len, u64::from(size.cnt));
+            *aggregate.entry(size.len).or_insert(0) += u64::from(size.cnt);
+        }
+        let dscp = class.unwrap_or_else(|| Dscp::from_tos(session.tos()));
+        println!(
+            "flow {} ({}): received {} of {} datagrams, {}",
+            i,
+            dscp,
+            r.cnt,
+            stats.packets,
+            framing.rates_of_sizes(&received, secs)
+        );
+        print_remarking(dscp.tos() | session.tos() & 3, r);
+        let rate = passed(r.cnt, r.dups);
+        rates.push(rate);
+        match classes.iter_mut().find(|(c, _)| *c == dscp) {
+            Some((_, rates)) => rates.push(rate),
+            None => classes.push((dscp, vec![rate])),
+        }
+    }
+    println!("aggregate: {}", framing.rates_of_sizes(&aggregate, secs));
+    if opt.flows.classified() {
+        for (dscp, rates) in &classes {
+            println!(
+                "class {}: {} flows, {:.0} pps passed, fairness index {:.3}",
+                dscp,
+                rates.len(),
+                rates.iter().sum::<f64>(),
+                fairness(rates)
+            );
+        }
+    }
+    println!("fairness index {:.3}", fairness(&rates));
+    println!("shaping: {:?}", classify(single, &rates));
+}
+
+/// Receiver side of a flow: the tracker of the epoch it is in.
+struct FlowState {
+    tracker: FlowTracker,
+    epoch: u32,
+}
+
+impl FlowState {
+    fn new() -> FlowState {
+        FlowState {
+            tracker: FlowTracker::new(),
+            epoch: 0,
+        }
+    }
+
+    /// Track a datagram, unless its epoch was reported already.
+    fn track(
+        &mut self,
+        payload: &SequencedPayload,
+        len: usize,
+        ts: Duration,
+        tos: Option<u8>,
+    ) {
+        if payload.epoch < self.epoch {
+            return;
+        } else if payload.epoch > self.epoch {
+            self.tracker = FlowTracker::new();
+            self.epoch = payload.epoch;
+        }
+        self.tracker.track(payload, len, ts, tos);
+    }
+
+    /// Report the epoch `epoch` with a throughput series at `interval` and
+    /// wait for the next one.
+    fn terminate(
+        &mut self,
+        epoch: u32,
+        interval: Duration,
+    ) -> SequenceReport {
+        let report = if epoch == self.epoch {
+            self.tracker.report(interval)
+        } else {
+            FlowTracker::new().report(interval)
+        };
+        self.tracker = FlowTracker::new();
+        self.epoch = epoch.wrapping_add(1);
+        report
+    }
+}
+
+/// Receive the epochs of a flow until it gets terminated.
+///
+/// Each epoch is tracked separately. Datagrams of epochs that were already
+/// reported are dropped. A client that punched a hole at the start gets
+/// its later punches answered, too.
+fn receive_flow(
+    sk: UdpSocket,
+    client: std::net::IpAddr,
+    worker_in: &mpsc::Receiver<ControlMessage>,
+    worker_out: &mpsc::Sender<ControlMessage>,
+) -> Result<(), String> {
+    let mut state = FlowState::new();
+
+    let peer = nat::answer_punch(&sk, client, PUNCH_TIMEOUT);
+    if let Some(peer) = peer {
+        println!("client punched from {}", peer);
+    }
 
-    println!("Wait for incoming flow...");
-    sk.peek(&mut buffer).expect("look for available data");
     sk.set_read_timeout(Some(Duration::from_millis(1000)))
         .expect("set timeout to detect finished flow");
+    let mut receiver = Receiver::from_socket(sk, RECV_BATCH);
 
     println!("Receive flow...");
     loop {
-        let bytes;
-        match sk.recv(&mut buffer) {
+        let cnt;
+        match receiver.recv() {
             Err(_) => {
-                // XXX check abort condition after timeout only
-                println!("check abort_cond");
-                if abort_cond() {
-                    println!("abort");
-                    break;
-                } else {
-                    println!("continue");
-                    continue;
+                // XXX check control messages after timeout only
+                let (terminate, interval, done) = match worker_in.try_recv() {
+                    Ok(ControlMessage::TerminateEpoch(_, e, interval)) => {
+                        (e, interval, false)
+                    }
+                    Ok(ControlMessage::TerminateFlow(_)) => {
+                        (state.epoch, SERIES_INTERVAL, true)
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                    _ => continue,
+                };
+                worker_out
+                    .send(ControlMessage::Report(
+                        state.terminate(terminate, interval),
+                    ))
+                    .map_err(|e| e.to_string())?;
+                if done {
+                    return Ok(());
                 }
+                continue;
             }
-            Ok(b) => {
-                bytes = b;
+            Ok(c) => {
+                cnt = c;
             }
         }
-        let payload: SequencedPayload =
-            serde_json::from_slice(&buffer[..bytes]).unwrap();
-        reseq.track(payload.seq);
+        for idx in 0..cnt {
+            let (data, ts) = receiver.datagram(idx);
+            let payload: SequencedPayload = match serde_json::from_slice(data)
+            {
+                Ok(payload) => payload,
+                Err(_) => {
+                    if let (Some(Punch::Request(_)), Some(peer)) =
+                        (Punch::parse(data), peer)
+                    {
+                        nat::reply(receiver.socket(), peer)
+                            .map_err(|e| e.to_string())?;
+                    }
+                    // keepalives and strays
+                    continue;
+                }
+            };
+            state.track(&payload, data.len(), ts, receiver.tos(idx));
+        }
     }
+}
 
-    SequenceReport {
-        last_seq: reseq.last_seq.unwrap_or(0),
-        missing: reseq.missing,
-        dups: reseq.dups,
-        cnt: reseq.cnt,
+/// A flow served on the shared UDP port.
+struct SharedFlow {
+    state: FlowState,
+    /// host of the client that requested the flow
+    client: std::net::IpAddr,
+    /// address of the client's punch, the only source of the flow
+    peer: Option<SocketAddr>,
+    worker_in: mpsc::Receiver<ControlMessage>,
+    worker_out: mpsc::Sender<ControlMessage>,
+    /// termination that waits for the flow to fall quiet
+    pending: Option<ControlMessage>,
+    /// arrival of the flow's latest datagram
+    last: Instant,
+}
+
+impl SharedFlow {
+    /// Answer a pending termination once the flow fell quiet, like a flow
+    /// on a port of its own does. Returns whether the flow goes on.
+    fn poll(&mut self) -> bool {
+        if self.pending.is_none() {
+            match self.worker_in.try_recv() {
+                Ok(msg) => self.pending = Some(msg),
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
+            }
+        }
+        if self.last.elapsed() < FLOW_LINGER {
+            return true;
+        }
+        let (terminate, interval, done) = match self.pending.take() {
+            Some(ControlMessage::TerminateEpoch(_, e, interval)) => {
+                (e, interval, false)
+            }
+            Some(ControlMessage::TerminateFlow(_)) => {
+                (self.state.epoch, SERIES_INTERVAL, true)
+            }
+            _ => return true,
+        };
+        let report = self.state.terminate(terminate, interval);
+        self.worker_out.send(ControlMessage::Report(report)).is_ok() && !done
     }
 }
 
-fn serve_client(mut ctrl_sk: TcpStream) -> Result<(), String> {
+/// Serves the flows of all clients on a single UDP port, so that a
+/// firewall in front of the server has to let through that port only.
+///
+/// Flows are told apart by the id in their payload.
+#[derive(Clone)]
+struct SharedPort {
+    port: u16,
+    flows: mpsc::Sender<(u16, SharedFlow)>,
+    /// ids of the flows served
+    ids: Arc<Mutex<HashSet<u16>>>,
+}
+
+impl SharedPort {
+    fn bind(host: &str, port: u16) -> Result<SharedPort, String> {
+        let sk = UdpSocket::bind((host, port)).map_err(|e| e.to_string())?;
+        let port = sk.local_addr().map_err(|e| e.to_string())?.port();
+        let (flows, added) = mpsc::channel();
+        let ids: Arc<Mutex<HashSet<u16>>> = Arc::default();
+        let served = ids.clone();
+        thread::spawn(move || {
+            receive_shared(sk, &added, &served).unwrap_or_else(|e| {
+                println!("Error on shared port {}: {}", port, e);
+            })
+        });
+        Ok(SharedPort { port, flows, ids })
+    }
+
+    /// Serve another flow of the client at `client` on the shared port.
+    ///
+    /// Flows get random ids that are not in use. The first punch from the
+    /// client's host binds the flow to its source address.
+    fn spawn_flow(
+        &self,
+        client: std::net::IpAddr,
+    ) -> Result<FlowWorker, String> {
+        let id = {
+            let mut ids = self.ids.lock().unwrap();
+            if ids.len() > usize::from(u16::MAX) {
+                return Err("no flow id left on the shared port".to_string());
+            }
+            loop {
+                let id = RandomState::new().build_hasher().finish() as u16;
+                if ids.insert(id) {
+                    break id;
+                }
+            }
+        };
+        let (worker_in_prod, worker_in_cons) = mpsc::channel();
+        let (worker_out_prod, worker_out_cons) = mpsc::channel();
+        let flow = SharedFlow {
+            state: FlowState::new(),
+            client,
+            peer: None,
+            worker_in: worker_in_cons,
+            worker_out: worker_out_prod,
+            pending: None,
+            last: Instant::now(),
+        };
+        self.flows
+            .send((id, flow))
+            .map_err(|_| "shared port is gone".to_string())?;
+
+        Ok(FlowWorker {
+            worker: None,
+            worker_in: worker_in_prod,
+            worker_out: worker_out_cons,
+            port: id,
+        })
+    }
+}
+
+fn receive_shared(
+    sk: UdpSocket,
+    added: &mpsc::Receiver<(u16, SharedFlow)>,
+    ids: &Mutex<HashSet<u16>>,
+) -> Result<(), String> {
+    let mut flows: HashMap<u16, SharedFlow> = HashMap::new();
+
+    sk.set_read_timeout(Some(SHARED_POLL))
+        .map_err(|e| e.to_string())?;
+    let mut receiver = Receiver::from_socket(sk, RECV_BATCH);
+
+    loop {
+        flows.extend(added.try_iter());
+        // timeouts only let the flows check for control messages
+        let cnt = receiver.recv().unwrap_or(0);
+        for idx in 0..cnt {
+            let (data, ts) = receiver.datagram(idx);
+            let payload: SequencedPayload = match serde_json::from_slice(data)
+            {
+                Ok(payload) => payload,
+                Err(_) => {
+                    if let (Some(Punch::Request(id)), Some(peer)) =
+                        (Punch::parse(data), receiver.source(idx))
+                    {
+                        match flows.get_mut(&id) {
+                            Some(flow)
+                                if nat::unmap(peer).ip() == flow.client =>
+                            {
+                                flow.peer = Some(peer);
+                                nat::reply(receiver.socket(), peer)
+                                    .map_err(|e| e.to_string())?;
+                            }
+                            _ => (),
+                        }
+                    }
+                    continue;
+                }
+            };
+            let source = receiver.source(idx);
+            let flow = match payload.flow.and_then(|id| flows.get_mut(&id)) {
+                Some(flow) if flow.peer.is_some() && flow.peer == source => {
+                    flow
+                }
+                _ => continue,
+            };
+            flow.state
+                .track(&payload, data.len(), ts, receiver.tos(idx));
+            flow.last = Instant::now();
+        }
+        flows.retain(|id, flow| {
+            flow.poll() || {
+                ids.lock().unwrap().remove(id);
+                false
+            }
+        });
+    }
+}
+
+fn serve_client(
+    mut ctrl_sk: TcpStream,
+    shared: Option<SharedPort>,
+) -> Result<(), String> {
     let mut workers: Vec<FlowWorker> = Vec::new();
+    let mut downstreams: Vec<(u16, DownstreamSender)> = Vec::new();
+    let mut echoes: Vec<(u16, Echo)> = Vec::new();
 
     let host = ctrl_sk
         .local_addr()
         .expect("derive local ip from ctrl socket")
         .ip();
+    // the server sends to this host only, lest it reflect to others
+    let client = ctrl_sk
+        .peer_addr()
+        .map(|addr| nat::unmap(addr).ip())
+        .map_err(|e| e.to_string())?;
 
     loop {
         let message = ctrl_sk.recv_msg()?;
@@ -152,10 +1181,36 @@ fn serve_client(mut ctrl_sk: TcpStream) -> Result<(), String> {
 
         match message {
             ControlMessage::RequestFlow => {
-                let w = spawn_flow_worker(host)?;
-                ctrl_sk.send_msg(ControlMessage::ExpectFlow(w.port))?;
+                let (w, msg) = match shared {
+                    Some(ref shared) => {
+                        let w = shared.spawn_flow(client)?;
+                        let id = w.port;
+                        (w, ControlMessage::ExpectSharedFlow(shared.port, id))
+                    }
+                    None => {
+                        let w = spawn_flow_worker(host, client)?;
+                        let port = w.port;
+                        (w, ControlMessage::ExpectFlow(port))
+                    }
+                };
+                ctrl_sk.send_msg(msg)?;
                 workers.push(w);
             }
+            ControlMessage::TerminateEpoch(port, epoch, interval) => {
+                let w = workers
+                    .iter()
+                    .find(|w| w.port == port)
+                    .ok_or("no flow served for that port")?;
+                w.worker_in
+                    .send(ControlMessage::TerminateEpoch(
+                        port, epoch, interval,
+                    ))
+                    .map_err(|e| e.to_string())?;
+                w.worker_out
+                    .recv()
+                    .map_err(|e| e.to_string())
+                    .and_then(|msg| ctrl_sk.send_msg(msg))?;
+            }
             ControlMessage::TerminateFlow(port) => {
                 let pos = workers
                     .iter()
@@ -169,7 +1224,93 @@ fn serve_client(mut ctrl_sk: TcpStream) -> Result<(), String> {
                     .recv()
                     .map_err(|e| e.to_string())
                     .and_then(|msg| ctrl_sk.send_msg(msg))?;
-                w.worker.join().expect("wait for worker thread")?
+                if let Some(worker) = w.worker {
+                    worker.join().expect("wait for worker thread")?
+                }
+            }
+            ControlMessage::RequestProbe(addr) => {
+                if nat::unmap(addr).ip() != client {
+                    return Err(format!("{} is not the client", addr));
+                }
+                let sk =
+                    UdpSocket::bind((host, 0)).map_err(|e| e.to_string())?;
+                for _ in 0..FILTER_PROBES {
+                    nat::reply(&sk, addr).map_err(|e| e.to_string())?;
+                }
+                ctrl_sk.send_msg(ControlMessage::ProbeSent)?;
+            }
+            ControlMessage::RequestTcpFlow => {
+                // the transfer takes over the connection
+                ctrl_sk.send_msg(ControlMessage::ExpectTcpFlow)?;
+                let report = tcp::receive_bulk(&mut ctrl_sk)
+                    .map_err(|e| e.to_string())?;
+                return ctrl_sk.send_msg(ControlMessage::TcpReport(report));
+            }
+            ControlMessage::RequestDownstream(..) if shared.is_some() => {
+                ctrl_sk.send_msg(ControlMessage::Refused(
+                    "downstream flows need a port of their own, which the \
+                     server's shared UDP port rules out"
+                        .to_string(),
+                ))?;
+            }
+            ControlMessage::RequestDownstream(pps, len, duration, tos) => {
+                let sk =
+                    UdpSocket::bind((host, 0)).map_err(|e| e.to_string())?;
+                let port = sk.local_addr().map_err(|e| e.to_string())?.port();
+                let sender = thread::spawn(move || {
+                    send_downstream(sk, client, pps, len, duration, tos)
+                });
+                downstreams.push((port, sender));
+                ctrl_sk.send_msg(ControlMessage::ExpectDownstream(port))?;
+            }
+            ControlMessage::TerminateDownstream(port) => {
+                let pos = downstreams
+                    .iter()
+                    .position(|flow| flow.0 == port)
+                    .ok_or("no downstream flow sent from that port")?;
+                let (_, sender) = downstreams.remove(pos);
+                let stats =
+                    sender.join().expect("wait for downstream sender")?;
+                ctrl_sk.send_msg(ControlMessage::DownstreamSent(stats))?;
+            }
+            ControlMessage::RequestEcho if shared.is_some() => {
+                ctrl_sk.send_msg(ControlMessage::Refused(
+                    "echo probes need a port of their own, which the \
+                     server's shared UDP port rules out"
+                        .to_string(),
+                ))?;
+            }
+            ControlMessage::RequestEcho => {
+                let sk =
+                    UdpSocket::bind((host, 0)).map_err(|e| e.to_string())?;
+                let port = sk.local_addr().map_err(|e| e.to_string())?.port();
+                let (stop, stopped) = mpsc::channel();
+                let echoer =
+                    thread::spawn(move || echo::echo(&sk, client, &stopped));
+                echoes.push((port, (stop, echoer)));
+                ctrl_sk.send_msg(ControlMessage::ExpectEcho(port))?;
+            }
+            ControlMessage::TerminateEcho(port) => {
+                let pos = echoes
+                    .iter()
+                    .position(|echo| echo.0 == port)
+                    .ok_or("no echo served on that port")?;
+                let (_, (stop, echoer)) = echoes.remove(pos);
+                // the echo also stops once the sender is gone
+                let _ = stop.send(());
+                let cnt = echoer
+                    .join()
+                    .expect("wait for echo")
+                    .map_err(|e| e.to_string())?;
+                ctrl_sk.send_msg(ControlMessage::EchoDone(cnt))?;
+            }
+            ControlMessage::TimeRequest(t1) => {
+                let t2 = as_ns(since_epoch());
+                ctrl_sk.send_msg(ControlMessage::TimeReply(
+                    t1,
+                    t2,
+                    as_ns(since_epoch()),
+                ))?;
             }
             _ => {
                 return Err("unsupported control message received".to_string())
@@ -178,14 +1319,57 @@ fn serve_client(mut ctrl_sk: TcpStream) -> Result<(), String> {
     }
 }
 
+/// Send a flow to the client on the host `client` once it punched a hole
+/// to `sk` and return the sender's statistics.
+fn send_downstream(
+    sk: UdpSocket,
+    client: std::net::IpAddr,
+    pps: u32,
+    len: usize,
+    duration: Duration,
+    tos: u8,
+) -> Result<FlowStats, String> {
+    let peer = nat::answer_punch(&sk, client, PUNCH_TIMEOUT)
+        .ok_or("no hole punched for the downstream flow")?;
+    sk.connect(peer).map_err(|e| e.to_string())?;
+
+    let mut seq = Sequencer::new();
+    let fill_packet = move |mut buf: Box<[u8]>| {
+        let payload = SequencedPayload {
+            epoch: 0,
+            seq: seq.next_seq(),
+            train: None,
+            flow: None,
+            sent_ns: Some(as_ns(since_epoch())),
+        };
+        payload.flatten_into(&mut buf)?;
+        Ok(buf)
+    };
+    let mut flow = Flow::from_socket(pps, len, duration, fill_packet, sk)
+        .with_tos(tos)
+        .with_late_fill(true);
+    Ok(flow.start_xmit())
+}
+
+/// Sender thread of a downstream flow.
+type DownstreamSender = thread::JoinHandle<Result<FlowStats, String>>;
+
+/// Stops an echo port and its thread.
+type Echo = (mpsc::Sender<()>, thread::JoinHandle<std::io::Result<u64>>);
+
 struct FlowWorker {
-    worker: thread::JoinHandle<Result<(), String>>,
+    /// thread of a flow on a port of its own
+    worker: Option<thread::JoinHandle<Result<(), String>>>,
     worker_in: mpsc::Sender<ControlMessage>,
     worker_out: mpsc::Receiver<ControlMessage>,
+    /// port of the flow, or its id on the shared port
     port: u16,
 }
 
-fn spawn_flow_worker(host: std::net::IpAddr) -> Result<FlowWorker, String> {
+fn spawn_flow_worker(
+    host: std::net::IpAddr,
+    client: std::net::IpAddr,
+) -> Result<FlowWorker, String> {
     let sk = UdpSocket::bind((host, 0)).map_err(|e| e.to_string())?;
 
     let port = sk
@@ -197,21 +1381,11 @@ fn spawn_flow_worker(host: std::net::IpAddr) -> Result<FlowWorker, String> {
         mpsc::channel::<ControlMessage>();
 
     let worker = thread::spawn(move || -> Result<(), String> {
-        let report = receive_flow(sk, || match worker_in_cons.try_recv() {
-            Ok(ControlMessage::TerminateFlow(_)) => true,
-            Err(mpsc::TryRecvError::Disconnected) => true,
-            _ => false,
-        });
-
-        worker_out_prod
-            .send(ControlMessage::Report(report))
-            .map_err(|e| e.to_string())?;
-
-        Ok(())
+        receive_flow(sk, client, &worker_in_cons, &worker_out_prod)
     });
 
     Ok(FlowWorker {
-        worker,
+        worker: Some(worker),
         worker_in: worker_in_prod,
         worker_out: worker_out_cons,
         port,
@@ -221,9 +1395,14 @@ fn spawn_flow_worker(host: std::net::IpAddr) -> Result<FlowWorker, String> {
 #[cfg(test)]
 mod tests {
     use analyze::sequence::{ReSequencer, Sequencer};
-    use analyze::SequencedPayload;
+    use analyze::series::SERIES_INTERVAL;
+    use analyze::{SequencedPayload, Session};
+    use control::ControlMessage;
     use flow::Flow;
-    use std::net::UdpSocket;
+    use nat::{self, NatType, Punch};
+    use profile::RateProfile;
+    use sizes::PacketSizes;
+    use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
     use std::num::Wrapping;
     use std::thread;
     use std::time::Duration;
@@ -260,9 +1439,13 @@ mod tests {
             Duration::from_secs(secs),
             move |mut buf: Box<[u8]>| {
                 let payload = SequencedPayload {
+                    epoch: 0,
                     seq: seq.next_seq(),
+                    train: None,
+                    flow: None,
+                    sent_ns: None,
                 };
-                payload.flatten_into(&mut buf);
+                payload.flatten_into(&mut buf)?;
                 Ok(buf)
             },
             sk_snd,
@@ -292,7 +1475,13 @@ mod tests {
                     ::serde_json::from_slice(&buffer[..bytes])
                         .unwrap_or_else(|_| {
                             println!("bytes: {}", bytes);
-                            SequencedPayload { seq: 0u32 }
+                            SequencedPayload {
+                                epoch: 0,
+                                seq: 0u32,
+                                train: None,
+                                flow: None,
+                                sent_ns: None,
+                            }
                         });
                 reseq.track(payload.seq);
             }
@@ -312,10 +1501,439 @@ mod tests {
             pps / (secs as u32)
         );
     }
+    /// Serve a single control connection on an ephemeral port.
+    pub fn spawn_server() -> SocketAddr {
+        spawn_server_with(None)
+    }
+
+    fn spawn_server_with(shared: Option<::SharedPort>) -> SocketAddr {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").expect("bind control port");
+        let addr = listener.local_addr().expect("get control port");
+        // bulk TCP transfers connect to the control port, too
+        thread::spawn(move || {
+            for ctrl_sk in listener.incoming() {
+                let ctrl_sk = ctrl_sk.expect("accept client");
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    ::serve_client(ctrl_sk, shared).unwrap_or_default()
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn session_trains() {
+        let mut session = Session::connect(spawn_server()).expect("connect");
+        let trains = [(400, 4), (800, 4), (1200, 4)];
+        let r = session
+            .run_trains(&trains, Duration::from_millis(1))
+            .expect("run trains");
+        session.close().expect("close session");
+
+        assert_eq!(r.cnt, 12);
+        assert_eq!(r.trains.len(), 3);
+        for (report, &(len, cnt)) in r.trains.iter().zip(trains.iter()) {
+            assert_eq!(report.len, len);
+            assert_eq!(report.cnt, cnt);
+        }
+    }
+
+    #[test]
+    fn session_large_datagrams() {
+        let mut session = Session::connect(spawn_server()).expect("connect");
+        let (stats, r) = session
+            .run_profile(
+                RateProfile::Constant(200),
+                "hist:1000=1,4000=1".parse().unwrap(),
+                Duration::from_millis(200),
+            )
+            .expect("run flow");
+        session.close().expect("close session");
+
+        assert_eq!(u64::from(r.cnt), stats.packets);
+        let received: Vec<(usize, u64)> =
+            r.sizes.iter().map(|s| (s.len, u64::from(s.cnt))).collect();
+        let sent: Vec<(usize, u64)> =
+            stats.sizes.iter().map(|(&len, &cnt)| (len, cnt)).collect();
+        assert_eq!(received, sent);
+        assert_eq!(received[1].0, 4000);
+    }
+
+    #[test]
+    fn session_refuse_reflection() {
+        use control::ControlStream;
+
+        let mut ctrl_sk =
+            TcpStream::connect(spawn_server()).expect("connect");
+        let third_party = "127.0.0.2:9".parse().unwrap();
+        ctrl_sk
+            .send_msg(ControlMessage::RequestProbe(third_party))
+            .expect("request probe");
+        assert!(ctrl_sk.recv_msg().is_err());
+    }
+
+    #[test]
+    fn session_nat() {
+        let mut session = Session::connect(spawn_server()).expect("connect");
+        let (local, mapped) = session.addresses().expect("addresses");
+        let nat_type = session.detect_nat().expect("detect NAT");
+        session.close().expect("close session");
+
+        assert_eq!(mapped, Some(local));
+        assert_eq!(nat_type, NatType::Open);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn session_probe_lens() {
+        let mut session = Session::connect(spawn_server()).expect("connect");
+        let r = session.probe_lens(&[1400, 1500], 2).expect("probe lengths");
+        session.close().expect("close session");
+
+        assert_eq!(r.cnt, 4);
+        assert_eq!(r.sizes.len(), 2);
+    }
+
+    #[test]
+    fn session_parallel() {
+        let mut session = Session::connect(spawn_server()).expect("connect");
+        let flows = session
+            .run_parallel(
+                &"2:ef,1".parse().expect("parse flows"),
+                RateProfile::Constant(200),
+                PacketSizes::Fixed(100),
+                Duration::from_millis(100),
+            )
+            .expect("run parallel flows");
+        assert_eq!(flows.len(), 3);
+        for (stats, r) in flows.iter() {
+            assert_eq!(u64::from(r.cnt), stats.packets);
+        }
+        // each flow is marked with its own class
+        if cfg!(target_os = "linux") {
+            let tos: Vec<_> =
+                flows.iter().map(|(_, r)| r.tos[0].tos).collect();
+            assert_eq!(tos, [0xb8, 0xb8, 0x00]);
+        }
+
+        // the flows' sockets are kept for further flows
+        let (stats, r) = session
+            .run_flow(200, 100, Duration::from_millis(100))
+            .expect("run flow");
+        assert_eq!(u64::from(r.cnt), stats.packets);
+        session.close().expect("close session");
+    }
+
+    #[test]
+    fn session_shared_port() {
+        let shared =
+            ::SharedPort::bind("127.0.0.1", 0).expect("bind shared port");
+        let mut session = Session::connect(spawn_server_with(Some(shared)))
+            .expect("connect");
+        let (_, mapped) = session.addresses().expect("addresses");
+        assert!(mapped.is_some());
+        assert!(session.shared());
+
+        // parallel flows are told apart on the same port
+        let flows = session
+            .run_parallel(
+                &"2".parse().expect("parse flows"),
+                RateProfile::Constant(200),
+                PacketSizes::Fixed(100),
+                Duration::from_millis(100),
+            )
+            .expect("run parallel flows");
+        for (stats, r) in flows.iter() {
+            assert_eq!(u64::from(r.cnt), stats.packets);
+        }
+        let r = session
+            .run_trains(&[(400, 4)], Duration::from_millis(1))
+            .expect("run trains");
+        assert_eq!(r.cnt, 4);
+        // TCP transfers go to the control port
+        let (stats, r) = session
+            .run_tcp(Duration::from_millis(100))
+            .expect("run TCP transfer");
+        assert_eq!(r.bytes, stats.bytes);
+        // requests for ports of their own are refused, the session goes on
+        assert!(session
+            .run_downstream(200, 100, Duration::from_millis(100))
+            .is_err());
+        assert!(session.measure_rtt(Duration::from_millis(100)).is_err());
+        let (stats, r) = session
+            .run_flow(200, 100, Duration::from_millis(100))
+            .expect("run flow");
+        assert_eq!(u64::from(r.cnt), stats.packets);
+        session.close().expect("close session");
+    }
+
+    #[test]
+    fn session_tcp() {
+        let mut session = Session::connect(spawn_server()).expect("connect");
+        let (stats, r) = session
+            .run_tcp(Duration::from_millis(200))
+            .expect("run TCP transfer");
+        // UDP flows go on after the transfer
+        let (flow, report) = session
+            .run_flow(200, 100, Duration::from_millis(100))
+            .expect("run flow");
+        session.close().expect("close session");
+
+        assert_eq!(r.bytes, stats.bytes);
+        assert_eq!(u64::from(report.cnt), flow.packets);
+    }
+
+    #[test]
+    fn session_bidirectional() {
+        let mut session = Session::connect(spawn_server()).expect("connect");
+        let duration = Duration::from_millis(200);
+        let (stats, r) = session
+            .run_downstream(500, 100, duration)
+            .expect("run downstream");
+        assert_eq!(u64::from(r.cnt), stats.packets);
+        assert!(r.delay.is_some());
+
+        session.set_timestamps(true);
+        session.set_series_interval(Duration::from_millis(50));
+        let (up, down) = session
+            .run_bidirectional(500, 500, 100, duration)
+            .expect("run both directions");
+        session.close().expect("close session");
+
+        for r in [&up.1, &down.1].iter() {
+            let series = r.series.as_ref().expect("throughput series");
+            assert_eq!(series.interval_ns, 50_000_000);
+            let packets: u32 = series.samples.iter().map(|s| s.packets).sum();
+            assert_eq!(packets, r.cnt);
+        }
+
+        assert_eq!(u64::from(up.1.cnt), up.0.packets);
+        assert_eq!(u64::from(down.1.cnt), down.0.packets);
+        // datagrams are stamped when sent, not when prepared ahead
+        for r in [&up.1, &down.1].iter() {
+            let delay = r.delay.as_ref().expect("delay report");
+            assert!(delay.p50_ns < 5_000_000, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn session_clock() {
+        let mut session = Session::connect(spawn_server()).expect("connect");
+        let offset = session.sync_clock().expect("synchronize clocks");
+        // same clock on both ends
+        assert!(offset.offset_ns.unsigned_abs() <= offset.uncertainty_ns);
+
+        session.set_timestamps(true);
+        let duration = Duration::from_millis(200);
+        let (_, up) = session.run_flow(500, 100, duration).expect("run flow");
+        let (_, down) = session
+            .run_downstream(500, 100, duration)
+            .expect("run downstream");
+        session.close().expect("close session");
+
+        for r in [up, down].iter() {
+            let delay = r.delay.as_ref().expect("delay report");
+            assert_eq!(
+                delay.clock.map(|c| c.uncertainty_ns),
+                Some(offset.uncertainty_ns)
+            );
+            let (min, uncertainty) = delay.one_way().expect("one-way delay");
+            assert!(min + uncertainty as i64 >= 0);
+        }
+    }
+
+    #[test]
+    fn session_rtt() {
+        let mut session = Session::connect(spawn_server()).expect("connect");
+        let duration = Duration::from_millis(200);
+        let idle = session.measure_rtt(duration).expect("measure rtt");
+        let ((stats, r), loaded) = session
+            .run_flow_with_rtt(500, 100, duration)
+            .expect("run flow with rtt");
+        session.close().expect("close session");
+
+        assert!(idle.sent > 0);
+        assert_eq!(idle.received, idle.sent);
+        assert_eq!(loaded.received, loaded.sent);
+        assert_eq!(u64::from(r.cnt), stats.packets);
+    }
+
+    #[test]
+    fn flow_worker_epochs() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let w =
+            ::spawn_flow_worker(localhost, localhost).expect("spawn worker");
+        let sk = UdpSocket::bind("127.0.0.1:0").expect("bind sender");
+        sk.connect(("127.0.0.1", w.port))
+            .expect("connect to worker");
+
+        for (epoch, cnt) in [(0u32, 3u32), (1, 5)].iter() {
+            for seq in 0..*cnt {
+                let mut buf = [0; 100];
+                let payload = SequencedPayload {
+                    epoch: *epoch,
+                    seq,
+                    train: None,
+                    flow: None,
+                    sent_ns: None,
+                };
+                payload.flatten_into(&mut buf).expect("flatten payload");
+                sk.send(&buf).expect("send datagram");
+            }
+            w.worker_in
+                .send(ControlMessage::TerminateEpoch(
+                    w.port,
+                    *epoch,
+                    SERIES_INTERVAL,
+                ))
+                .expect("terminate epoch");
+            match w.worker_out.recv().expect("receive report") {
+                ControlMessage::Report(r) => assert_eq!(r.cnt, *cnt),
+                _ => panic!("unexpected message"),
+            }
+        }
+
+        // a straggler from a reported epoch is dropped
+        let mut buf = [0; 100];
+        let payload = SequencedPayload {
+            epoch: 0,
+            seq: 3,
+            train: None,
+            flow: None,
+            sent_ns: None,
+        };
+        payload.flatten_into(&mut buf).expect("flatten payload");
+        sk.send(&buf).expect("send datagram");
+        w.worker_in
+            .send(ControlMessage::TerminateFlow(w.port))
+            .expect("terminate flow");
+        match w.worker_out.recv().expect("receive report") {
+            ControlMessage::Report(r) => assert_eq!(r.cnt, 0),
+            _ => panic!("unexpected message"),
+        }
+        w.worker
+            .expect("worker thread")
+            .join()
+            .expect("wait for worker")
+            .expect("worker result");
+    }
+
+    #[test]
+    fn downstream_client_only() {
+        let sk = UdpSocket::bind("127.0.0.1:0").expect("bind sender");
+        let to = sk.local_addr().expect("sender address");
+        let duration = Duration::from_millis(100);
+        let sender = thread::spawn(move || {
+            ::send_downstream(sk, to.ip(), 200, 100, duration, 0)
+        });
+
+        // a punch from another host gets neither reply nor flow
+        let stranger = UdpSocket::bind("127.0.0.2:0").expect("bind stranger");
+        stranger
+            .send_to(&Punch::Request(to.port()).to_vec(), to)
+            .expect("send punch");
+        assert!(!nat::await_datagram(&stranger, Duration::from_millis(200)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").expect("bind client");
+        client.connect(to).expect("connect to sender");
+        nat::punch(&client, None, to.port()).expect("punch");
+        let stats = sender
+            .join()
+            .expect("wait for sender")
+            .expect("send downstream");
+        assert!(stats.packets > 0);
+        assert!(!nat::await_datagram(&stranger, Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn shared_flow_source() {
+        let shared =
+            ::SharedPort::bind("127.0.0.1", 0).expect("bind shared port");
+        let client = "127.0.0.1".parse().unwrap();
+        let w = shared.spawn_flow(client).expect("spawn flow");
+        let other = shared.spawn_flow(client).expect("spawn flow");
+        assert_ne!(w.port, other.port);
+
+        // only the client's host may punch
+        let stranger = UdpSocket::bind("127.0.0.2:0").expect("bind stranger");
+        stranger
+            .connect(("127.0.0.1", shared.port))
+            .expect("connect to shared port");
+        stranger
+            .send(&Punch::Request(w.port).to_vec())
+            .expect("send punch");
+        assert!(!nat::await_datagram(&stranger, Duration::from_millis(200)));
+
+        let sk = UdpSocket::bind("127.0.0.1:0").expect("bind sender");
+        sk.connect(("127.0.0.1", shared.port))
+            .expect("connect to shared port");
+        nat::punch(&sk, None, w.port).expect("punch");
+        // datagrams of the flow from elsewhere than the punch are dropped
+        let spoof = UdpSocket::bind("127.0.0.1:0").expect("bind spoofer");
+        spoof
+            .connect(("127.0.0.1", shared.port))
+            .expect("connect to shared port");
+        for (sk, seq) in
+            [(&spoof, 0u32), (&sk, 0), (&sk, 1), (&spoof, 2)].iter()
+        {
+            let mut buf = [0; 100];
+            let payload = SequencedPayload {
+                epoch: 0,
+                seq: *seq,
+                train: None,
+                flow: Some(w.port),
+                sent_ns: None,
+            };
+            payload.flatten_into(&mut buf).expect("flatten payload");
+            sk.send(&buf).expect("send datagram");
+        }
+        w.worker_in
+            .send(ControlMessage::TerminateFlow(w.port))
+            .expect("terminate flow");
+        match w.worker_out.recv().expect("receive report") {
+            ControlMessage::Report(r) => assert_eq!(r.cnt, 2),
+            _ => panic!("unexpected message"),
+        }
+    }
+
     //#[test]
     // fn run_main() {
     //   ::mainymain(vec![String::from("qosmap"), String::from("-h")]);
     // }
+    #[test]
+    fn monitor_run_fails() {
+        use monitor::Store;
+        use std::env;
+        use structopt::StructOpt;
+
+        let opt = |args: &[&str]| {
+            ::Opt::from_iter_safe(
+                ["qosmap", "127.0.0.1", "--monitor", "60"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        // single flow runs store no rate search results
+        assert!(opt(&["--sizes", "imix"]).is_err());
+        assert!(opt(&["--flows", "2"]).is_err());
+
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+            listener.local_addr().expect("free port").port().to_string()
+        };
+        let opt = opt(&["-p", &port]).expect("monitor options");
+        let path = env::temp_dir().join(format!(
+            "qosmap-monitor-run-{}.jsonl",
+            ::std::process::id()
+        ));
+        let store = Store::new(path);
+        // a failed run is reported and leaves the store alone
+        assert!(::monitor_run("127.0.0.1", &opt, &store).is_err());
+        assert_eq!(store.last(), Ok(None));
+    }
+
     #[test]
     #[should_panic(expected = "generate the requested rate")]
     fn run_main_server_client() {
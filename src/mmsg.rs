@@ -0,0 +1,203 @@
+//! Batched datagram I/O for Linux.
+
+extern crate libc;
+
+use receiver::Dgram;
+use sockaddr;
+use std::io;
+use std::mem;
+use std::net::UdpSocket;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::time::Duration;
+use tos;
+
+/// `UDP_SEGMENT` from `linux/udp.h`; libc only exports it for some targets.
+const UDP_SEGMENT: libc::c_int = 103;
+
+/// The kernel refuses GSO sends with more segments than this.
+pub const GSO_MAX_SEGMENTS: usize = 64;
+
+fn iovecs(bufs: &[Box<[u8]>]) -> Vec<libc::iovec> {
+    bufs.iter()
+        .map(|b| libc::iovec {
+            iov_base: b.as_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        })
+        .collect()
+}
+
+/// Send every buffer as a datagram of its own with a single `sendmmsg`.
+///
+/// Returns the number of datagrams the kernel accepted.
+pub fn send_batch(sk: &UdpSocket, bufs: &[Box<[u8]>]) -> io::Result<usize> {
+    let mut iov = iovecs(bufs);
+    let mut msgs: Vec<libc::mmsghdr> = iov
+        .iter_mut()
+        .map(|iov| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    let ret = unsafe {
+        libc::sendmmsg(
+            sk.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            0,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Send the buffers as one UDP GSO super-datagram that gets split into
+/// datagrams of `segment` bytes further down the stack.
+///
+/// All buffers but the last one must be exactly `segment` bytes long and
+/// there must not be more than `GSO_MAX_SEGMENTS` of them.
+pub fn send_segmented(
+    sk: &UdpSocket,
+    bufs: &[Box<[u8]>],
+    segment: u16,
+) -> io::Result<usize> {
+    let mut iov = iovecs(bufs);
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 4];
+    let control_len = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as _) };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = iov.as_mut_ptr();
+    msg.msg_iovlen = iov.len() as _;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control_len as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as _) as _;
+        *(libc::CMSG_DATA(cmsg) as *mut u16) = segment;
+    }
+
+    let ret = unsafe { libc::sendmsg(sk.as_raw_fd(), &msg, 0) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(bufs.len())
+    }
+}
+
+/// Have the kernel timestamp incoming datagrams (`SO_TIMESTAMPNS`).
+pub fn enable_timestamps(sk: &UdpSocket) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            sk.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Control buffer of a single received datagram; u64 keeps it aligned.
+type Control = [u64; 8];
+
+fn rx_timestamp(msg: &libc::msghdr) -> Option<Duration> {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET
+                && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS
+            {
+                let ts: libc::timespec = ptr::read_unaligned(
+                    libc::CMSG_DATA(cmsg) as *const libc::timespec,
+                );
+                return Some(Duration::new(
+                    ts.tv_sec as u64,
+                    ts.tv_nsec as u32,
+                ));
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    None
+}
+
+/// Receive up to `bufs.len()` datagrams with a single `recvmmsg`.
+///
+/// Blocks (subject to the socket's read timeout) until at least one
+/// datagram is available. For each datagram its length and, if enabled,
+/// its kernel receive timestamp, TOS byte and source are stored in
+/// `dgrams`.
+/// Returns the number of datagrams received.
+pub fn recv_batch(
+    sk: &UdpSocket,
+    bufs: &mut [Box<[u8]>],
+    dgrams: &mut [Dgram],
+) -> io::Result<usize> {
+    let mut iov: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|b| libc::iovec {
+            iov_base: b.as_mut_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        })
+        .collect();
+    let mut control: Vec<Control> = vec![[0; 8]; bufs.len()];
+    let mut names: Vec<_> =
+        bufs.iter().map(|_| sockaddr::storage()).collect();
+    let mut msgs: Vec<libc::mmsghdr> = iov
+        .iter_mut()
+        .zip(control.iter_mut())
+        .zip(names.iter_mut())
+        .map(|((iov, control), name)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = name as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of_val(name) as _;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut _;
+            msg.msg_hdr.msg_controllen = mem::size_of::<Control>() as _;
+            msg
+        })
+        .collect();
+
+    let ret = unsafe {
+        libc::recvmmsg(
+            sk.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            libc::MSG_WAITFORONE as _,
+            ptr::null_mut(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let cnt = ret as usize;
+    for ((msg, name), dgram) in
+        msgs.iter().zip(&names).zip(dgrams.iter_mut()).take(cnt)
+    {
+        *dgram = (
+            msg.msg_len as usize,
+            rx_timestamp(&msg.msg_hdr),
+            tos::rx_tos(&msg.msg_hdr),
+            sockaddr::to_std(name),
+        );
+    }
+    Ok(cnt)
+}
@@ -0,0 +1,206 @@
+extern crate serde_json;
+
+use bitrate::{Bitrate, LinkOverhead};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+use tos::Dscp;
+
+/// Runs start up to this share of the interval early or late, so that
+/// monitors started at the same time do not probe the path at once.
+const JITTER: f64 = 0.1;
+
+/// Result of the rate search for one DSCP class.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub dscp: Dscp,
+    pub overhead: LinkOverhead,
+    /// UDP goodput of the long datagrams at the maximum rate
+    pub goodput: Bitrate,
+}
+
+/// Results of a monitoring run.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Record {
+    /// start of the run in seconds since the unix epoch
+    pub time: u64,
+    pub server: String,
+    pub mappings: Vec<Mapping>,
+}
+
+/// How far results may move before an alert is raised.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// change of the goodput as a share of the previous one
+    pub rate: f64,
+    /// change of the overhead in bytes
+    pub overhead: i64,
+}
+
+/// Records of monitoring runs, one JSON object per line of a file.
+pub struct Store {
+    path: PathBuf,
+}
+
+impl Store {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Store {
+        Store { path: path.into() }
+    }
+
+    /// The latest record, if any.
+    pub fn last(&self) -> Result<Option<Record>, String> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut last = None;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            if !line.trim().is_empty() {
+                last = Some(line);
+            }
+        }
+        match last {
+            Some(line) => serde_json::from_str(&line)
+                .map(Some)
+                .map_err(|e| format!("{}: {}", self.path.display(), e)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn append(&self, record: &Record) -> Result<(), String> {
+        let mut line =
+            serde_json::to_vec(record).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// Describe how the results of `cur` moved beyond `thresholds` compared
+/// to `prev`, per DSCP class both measured.
+pub fn changes(
+    prev: &Record,
+    cur: &Record,
+    thresholds: Thresholds,
+) -> Vec<String> {
+    let mut changes = vec![];
+    for m in &cur.mappings {
+        let p = match prev.mappings.iter().find(|p| p.dscp == m.dscp) {
+            Some(p) => p,
+            None => continue,
+        };
+        let share = m.goodput.0 as f64 / p.goodput.0.max(1) as f64 - 1.0;
+        if share.abs() > thresholds.rate {
+            changes.push(format!(
+                "DSCP class {}: goodput {} -> {} ({:+.0}%)",
+                m.dscp,
+                p.goodput,
+                m.goodput,
+                share * 100.0
+            ));
+        }
+        if (m.overhead.l3 - p.overhead.l3).abs() > thresholds.overhead {
+            changes.push(format!(
+                "DSCP class {}: overhead {} -> {} bytes on top of the IP \
+                 packet",
+                m.dscp, p.overhead.l3, m.overhead.l3
+            ));
+        }
+    }
+    changes
+}
+
+/// Run `command` with the shell, with the `changes` one per line in
+/// `QOSMAP_CHANGES` and the record in `QOSMAP_RECORD`.
+pub fn alert(
+    command: &str,
+    changes: &[String],
+    record: &Record,
+) -> Result<(), String> {
+    let record = serde_json::to_string(record).map_err(|e| e.to_string())?;
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("QOSMAP_CHANGES", changes.join("\n"))
+        .env("QOSMAP_RECORD", record)
+        .status()
+        .map_err(|e| format!("{}: {}", command, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{}: {}", command, status))
+    }
+}
+
+/// `interval` moved by up to `JITTER` of it, as `random` in [0, 1) says.
+pub fn jittered(interval: Duration, random: f64) -> Duration {
+    interval.mul_f64(1.0 + JITTER * (2.0 * random - 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn record(time: u64, l3: i64, mbit: u64) -> Record {
+        Record {
+            time,
+            server: "192.0.2.1:4801".to_string(),
+            mappings: vec![Mapping {
+                dscp: Dscp(0),
+                overhead: LinkOverhead {
+                    payload: l3 + 28,
+                    l3,
+                    l2: l3 - 18,
+                },
+                goodput: Bitrate(mbit * 1_000_000),
+            }],
+        }
+    }
+
+    #[test]
+    fn monitor_changes() {
+        let path = env::temp_dir()
+            .join(format!("qosmap-monitor-{}.jsonl", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = Store::new(path.clone());
+        assert_eq!(store.last(), Ok(None));
+        store.append(&record(1, 18, 50)).expect("append");
+        store.append(&record(2, 18, 48)).expect("append");
+        let prev = store.last().expect("read store").expect("record");
+        assert_eq!(prev, record(2, 18, 48));
+
+        let thresholds = Thresholds {
+            rate: 0.1,
+            overhead: 4,
+        };
+        assert!(changes(&prev, &record(3, 20, 45), thresholds).is_empty());
+        let changed = changes(&prev, &record(3, 34, 20), thresholds);
+        assert_eq!(changed.len(), 2);
+        assert!(changed[0].contains("-58%"));
+
+        let out = path.with_extension("alert");
+        let command = format!("echo \"$QOSMAP_CHANGES\" > {}", out.display());
+        alert(&command, &changed, &prev).expect("alert");
+        let alerted = fs::read_to_string(&out).expect("alert output");
+        assert_eq!(alerted.lines().count(), 2);
+        assert!(alert("exit 3", &changed, &prev).is_err());
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&out);
+
+        let minute = Duration::from_secs(60);
+        assert_eq!(jittered(minute, 0.5), minute);
+        assert_eq!(jittered(minute, 0.0), Duration::from_secs(54));
+    }
+}
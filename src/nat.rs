@@ -0,0 +1,284 @@
+extern crate serde_json;
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Hole punching attempts before giving up.
+const PUNCH_TRIES: u32 = 10;
+/// Time to wait for the server's reply to a punch.
+const PUNCH_WAIT: Duration = Duration::from_millis(200);
+/// Punches and keepalives are far shorter than this.
+const PUNCH_LEN: usize = 256;
+
+/// Datagrams that open and keep open the path through a NAT. They are
+/// told apart from flow datagrams by their JSON.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum Punch {
+    /// sent by the client on a new flow socket, with the flow's id or
+    /// port
+    Request(u16),
+    /// the server's answer with the address it saw the request from
+    Reply(SocketAddr),
+    /// refreshes the NAT mapping while a flow is idle
+    Keepalive,
+}
+
+impl Punch {
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("serialize punch")
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Punch> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+/// How a NAT between client and server maps and filters UDP.
+#[derive(Debug, PartialEq)]
+pub enum NatType {
+    /// no NAT and unsolicited datagrams get through
+    Open,
+    /// no NAT, but a firewall drops unsolicited datagrams
+    Firewall,
+    /// one mapping per socket, other ports of a contacted host may send
+    /// to it; a full cone and an address-restricted cone NAT look alike
+    Cone,
+    /// one mapping per socket, only contacted ports may send to it
+    PortRestrictedCone,
+    /// a new mapping per destination port, which defeats hole punching
+    /// with third parties
+    Symmetric,
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            NatType::Open => "no NAT",
+            NatType::Firewall => "no NAT, filtering firewall",
+            NatType::Cone => "full or address-restricted cone NAT",
+            NatType::PortRestrictedCone => "port-restricted cone NAT",
+            NatType::Symmetric => "symmetric NAT",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Classify the NAT from the client's `local` address, the addresses the
+/// server saw it at from two of its ports and whether a datagram from a
+/// port the client never contacted got through.
+///
+/// All probes involve a single server address, so NATs that map or
+/// filter by address only are not told apart from ones that do neither.
+pub fn classify(
+    local: SocketAddr,
+    mapped: &[SocketAddr],
+    unsolicited: bool,
+) -> NatType {
+    if mapped.iter().all(|&addr| unmap(addr) == local) {
+        if unsolicited {
+            NatType::Open
+        } else {
+            NatType::Firewall
+        }
+    } else if mapped.windows(2).any(|pair| pair[0] != pair[1]) {
+        NatType::Symmetric
+    } else if unsolicited {
+        NatType::Cone
+    } else {
+        NatType::PortRestrictedCone
+    }
+}
+
+/// The IPv4 address behind an IPv4-mapped IPv6 one.
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Punch a hole through NATs and firewalls on the client's way to the
+/// server and return the address the server sees the client at.
+///
+/// `sk` is connected to the server's flow port, or sends to `to`. `flow`
+/// tells the server which flow the socket belongs to.
+pub fn punch(
+    sk: &UdpSocket,
+    to: Option<SocketAddr>,
+    flow: u16,
+) -> Result<SocketAddr, String> {
+    let timeout = sk.read_timeout().map_err(|e| e.to_string())?;
+    sk.set_read_timeout(Some(PUNCH_WAIT))
+        .map_err(|e| e.to_string())?;
+    let mapped = punch_tries(sk, to, flow);
+    sk.set_read_timeout(timeout).map_err(|e| e.to_string())?;
+    mapped
+}
+
+fn punch_tries(
+    sk: &UdpSocket,
+    to: Option<SocketAddr>,
+    flow: u16,
+) -> Result<SocketAddr, String> {
+    let request = Punch::Request(flow).to_vec();
+    let mut buf = [0; PUNCH_LEN];
+    for _ in 0..PUNCH_TRIES {
+        match to {
+            Some(to) => sk.send_to(&request, to),
+            None => sk.send(&request),
+        }
+        .map_err(|e| e.to_string())?;
+        let len = match sk.recv_from(&mut buf) {
+            Ok((len, from)) if to.is_none() || to == Some(from) => len,
+            Ok(_) => continue,
+            Err(ref e) if is_timeout(e) => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+        if let Some(Punch::Reply(mapped)) = Punch::parse(&buf[..len]) {
+            return Ok(mapped);
+        }
+    }
+    Err("no reply to hole punching".to_string())
+}
+
+/// Wait up to `timeout` for the client on the host `client` to punch a
+/// hole and answer it. Returns the address the client was seen at.
+///
+/// Datagrams from other hosts are dropped, lest the server send to them.
+/// Other datagrams of the client are left on the socket.
+pub fn answer_punch(
+    sk: &UdpSocket,
+    client: IpAddr,
+    timeout: Duration,
+) -> Option<SocketAddr> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; PUNCH_LEN];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        sk.set_read_timeout(Some(deadline - now)).ok()?;
+        let (len, peer) = sk.peek_from(&mut buf).ok()?;
+        if unmap(peer).ip() != client {
+            sk.recv_from(&mut buf).ok()?;
+            continue;
+        }
+        match Punch::parse(&buf[..len]) {
+            Some(Punch::Request(_)) => (),
+            _ => return None,
+        }
+        sk.recv_from(&mut buf).ok()?;
+        reply(sk, peer).ok()?;
+        return Some(peer);
+    }
+}
+
+/// Tell `peer` which address its punch came from.
+pub fn reply(sk: &UdpSocket, peer: SocketAddr) -> io::Result<()> {
+    sk.send_to(&Punch::Reply(unmap(peer)).to_vec(), peer)
+        .map(|_| ())
+}
+
+/// Whether any datagram arrives on `sk` within `timeout`.
+pub fn await_datagram(sk: &UdpSocket, timeout: Duration) -> bool {
+    let mut buf = [0; PUNCH_LEN];
+    sk.set_read_timeout(Some(timeout)).is_ok()
+        && sk.recv_from(&mut buf).is_ok()
+}
+
+/// Whether a socket operation ran into its timeout.
+pub fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+        || e.kind() == io::ErrorKind::TimedOut
+}
+
+/// Sends keepalives on the flow sockets of a session, so that NAT
+/// mappings do not expire between flows. Stops when dropped.
+pub struct Keepalive {
+    socks: Arc<Mutex<Vec<UdpSocket>>>,
+    _stop: mpsc::Sender<()>,
+}
+
+impl Keepalive {
+    pub fn start(interval: Duration) -> Keepalive {
+        let socks: Arc<Mutex<Vec<UdpSocket>>> = Arc::default();
+        let (stop, stopped) = mpsc::channel();
+        let keepalive = Punch::Keepalive.to_vec();
+        let shared = socks.clone();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(interval)
+            {
+                for sk in shared.lock().unwrap().iter() {
+                    // a lost keepalive is made up for by the next one
+                    let _ = sk.send(&keepalive);
+                }
+            }
+        });
+        Keepalive { socks, _stop: stop }
+    }
+
+    /// Keep the mapping of the connected socket `sk` open, too.
+    pub fn add(&self, sk: &UdpSocket) -> io::Result<()> {
+        let sk = sk.try_clone()?;
+        self.socks.lock().unwrap().push(sk);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nat_classify() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let local = addr("192.168.1.2:5000");
+        let public = addr("203.0.113.7:6000");
+
+        assert_eq!(classify(local, &[local, local], true), NatType::Open);
+        let mapped = addr("[::ffff:192.168.1.2]:5000");
+        assert_eq!(classify(local, &[mapped], false), NatType::Firewall);
+        assert_eq!(classify(local, &[public, public], true), NatType::Cone);
+        assert_eq!(
+            classify(local, &[public, public], false),
+            NatType::PortRestrictedCone
+        );
+        let other = addr("203.0.113.7:6001");
+        assert_eq!(
+            classify(local, &[public, other], true),
+            NatType::Symmetric
+        );
+    }
+
+    #[test]
+    fn nat_punch() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind server");
+        let client = UdpSocket::bind("127.0.0.1:0").expect("bind client");
+        let to = server.local_addr().unwrap();
+        client.connect(to).expect("connect client");
+        let host = to.ip();
+        let answer = thread::spawn(move || {
+            answer_punch(&server, host, PUNCH_WAIT * 10)
+        });
+
+        // punches of other hosts go unanswered
+        let stranger = UdpSocket::bind("127.0.0.2:0").expect("bind stranger");
+        stranger
+            .send_to(&Punch::Request(to.port()).to_vec(), to)
+            .expect("send punch");
+        assert!(!await_datagram(&stranger, PUNCH_WAIT));
+        let mapped = punch(&client, None, to.port()).expect("punch");
+        assert_eq!(mapped, client.local_addr().unwrap());
+        assert_eq!(answer.join().unwrap(), Some(mapped));
+    }
+}
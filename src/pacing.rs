@@ -0,0 +1,311 @@
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+use std::io;
+use std::net::UdpSocket;
+use std::str::FromStr;
+use std::thread::{sleep, yield_now};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use std::mem;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// How the sender waits for the time slot of the next datagram.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    /// sleep until the slot is due
+    Sleep,
+    /// sleep until shortly before the slot and busy-wait the rest
+    Hybrid,
+    /// wait on a timerfd (Linux only)
+    TimerFd,
+    /// hand datagrams to the kernel ahead of time with SO_TXTIME and let
+    /// an etf qdisc (clockid CLOCK_TAI) release them (Linux only)
+    TxTime,
+}
+
+impl FromStr for Pacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pacing, String> {
+        match s {
+            "sleep" => Ok(Pacing::Sleep),
+            "hybrid" => Ok(Pacing::Hybrid),
+            #[cfg(target_os = "linux")]
+            "timerfd" => Ok(Pacing::TimerFd),
+            #[cfg(target_os = "linux")]
+            "txtime" => Ok(Pacing::TxTime),
+            _ => Err(format!("unsupported pacing mode: {}", s)),
+        }
+    }
+}
+
+/// How well the sender kept to the requested rate.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct PacingStats {
+    /// datagrams per second actually handed to the socket
+    pub achieved_pps: f64,
+    /// share of the scheduled time slots that were served
+    pub accuracy: f64,
+    /// standard deviation of the send times from their slots
+    pub jitter: Duration,
+}
+
+/// Collects how far datagrams were sent from their time slots.
+pub struct SendTimes {
+    cnt: u64,
+    sum_ns: f64,
+    sum_sq_ns: f64,
+    max_lateness: Duration,
+}
+
+impl SendTimes {
+    pub fn new() -> SendTimes {
+        SendTimes {
+            cnt: 0,
+            sum_ns: 0.0,
+            sum_sq_ns: 0.0,
+            max_lateness: Duration::new(0, 0),
+        }
+    }
+
+    pub fn track(&mut self, slot: Instant, sent: Instant) {
+        let ns = if sent >= slot {
+            self.max_lateness = self.max_lateness.max(sent - slot);
+            as_ns(sent - slot)
+        } else {
+            -as_ns(slot - sent)
+        };
+        self.cnt += 1;
+        self.sum_ns += ns;
+        self.sum_sq_ns += ns * ns;
+    }
+
+    pub fn max_lateness(&self) -> Duration {
+        self.max_lateness
+    }
+
+    pub fn stats(&self, elapsed: Duration, scheduled: u64) -> PacingStats {
+        if self.cnt == 0 {
+            return PacingStats::default();
+        }
+        let cnt = self.cnt as f64;
+        let mean = self.sum_ns / cnt;
+        let variance = (self.sum_sq_ns / cnt - mean * mean).max(0.0);
+        let achieved_pps = cnt / (as_ns(elapsed) / 1e9);
+        PacingStats {
+            achieved_pps,
+            accuracy: cnt / scheduled as f64,
+            jitter: Duration::from_nanos(variance.sqrt() as u64),
+        }
+    }
+}
+
+fn as_ns(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e9 + f64::from(d.subsec_nanos())
+}
+
+/// Hybrid pacing sleeps until this long before the deadline.
+const SPIN_MARGIN: Duration = Duration::from_micros(200);
+/// With SO_TXTIME, datagrams are handed over this long before their slot.
+const TXTIME_LOOKAHEAD: Duration = Duration::from_millis(2);
+
+pub struct Pacer {
+    pacing: Pacing,
+    #[cfg(target_os = "linux")]
+    timerfd: Option<RawFd>,
+    /// CLOCK_TAI in ns at `Instant` to translate slots into txtimes
+    #[cfg(target_os = "linux")]
+    tai_ref: (Instant, u64),
+}
+
+impl Pacer {
+    #[cfg(target_os = "linux")]
+    pub fn new(pacing: Pacing, sk: &UdpSocket) -> io::Result<Pacer> {
+        let timerfd = if pacing == Pacing::TimerFd {
+            let fd =
+                unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Some(fd)
+        } else {
+            None
+        };
+        if pacing == Pacing::TxTime {
+            enable_txtime(sk)?;
+        }
+        Ok(Pacer {
+            pacing,
+            timerfd,
+            tai_ref: (Instant::now(), clock_ns(libc::CLOCK_TAI)),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(pacing: Pacing, _sk: &UdpSocket) -> io::Result<Pacer> {
+        Ok(Pacer { pacing })
+    }
+
+    /// How long before its slot a datagram may be handed to the socket.
+    pub fn lookahead(&self) -> Duration {
+        match self.pacing {
+            Pacing::TxTime => TXTIME_LOOKAHEAD,
+            _ => Duration::new(0, 0),
+        }
+    }
+
+    pub fn wait_until(&mut self, deadline: Instant) {
+        let now = Instant::now();
+        if deadline <= now {
+            return;
+        }
+        match self.pacing {
+            Pacing::Sleep | Pacing::TxTime => sleep(deadline - now),
+            Pacing::Hybrid => {
+                if deadline - now > SPIN_MARGIN {
+                    sleep(deadline - now - SPIN_MARGIN);
+                }
+                while Instant::now() < deadline {
+                    yield_now();
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Pacing::TimerFd => self.wait_timerfd(deadline, deadline - now),
+            #[cfg(not(target_os = "linux"))]
+            Pacing::TimerFd => sleep(deadline - now),
+        }
+    }
+
+    /// Wait `timeout` on the timerfd, or sleep until `deadline` if the
+    /// timerfd fails.
+    #[cfg(target_os = "linux")]
+    fn wait_timerfd(&mut self, deadline: Instant, timeout: Duration) {
+        let fd = self.timerfd.expect("timerfd pacing without a timerfd");
+        let mut spec: libc::itimerspec = unsafe { mem::zeroed() };
+        spec.it_value.tv_sec = timeout.as_secs() as libc::time_t;
+        spec.it_value.tv_nsec = timeout.subsec_nanos() as libc::c_long;
+        let mut expirations = 0u64;
+        let expired = unsafe {
+            libc::timerfd_settime(fd, 0, &spec, std::ptr::null_mut()) == 0
+                && libc::read(
+                    fd,
+                    &mut expirations as *mut u64 as *mut libc::c_void,
+                    mem::size_of::<u64>(),
+                ) == mem::size_of::<u64>() as isize
+        };
+        let now = Instant::now();
+        if !expired && deadline > now {
+            sleep(deadline - now);
+        }
+    }
+
+    /// Send `data` to be released by the qdisc at `at`.
+    #[cfg(target_os = "linux")]
+    pub fn send_at(
+        &self,
+        sk: &UdpSocket,
+        data: &[u8],
+        at: Instant,
+    ) -> io::Result<usize> {
+        let txtime = if at > self.tai_ref.0 {
+            let d = at - self.tai_ref.0;
+            self.tai_ref.1
+                + d.as_secs() * 1_000_000_000
+                + d.subsec_nanos() as u64
+        } else {
+            self.tai_ref.1
+        };
+
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        // u64 keeps the control buffer aligned for cmsghdr
+        let mut control = [0u64; 4];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen =
+            unsafe { libc::CMSG_SPACE(mem::size_of::<u64>() as _) } as _;
+
+        let ret = unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_TXTIME;
+            (*cmsg).cmsg_len =
+                libc::CMSG_LEN(mem::size_of::<u64>() as _) as _;
+            *(libc::CMSG_DATA(cmsg) as *mut u64) = txtime;
+            libc::sendmsg(sk.as_raw_fd(), &msg, 0)
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Pacer {
+    fn drop(&mut self) {
+        if let Some(fd) = self.timerfd {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn enable_txtime(sk: &UdpSocket) -> io::Result<()> {
+    let txtime = libc::sock_txtime {
+        clockid: libc::CLOCK_TAI,
+        flags: 0,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            sk.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TXTIME,
+            &txtime as *const libc::sock_txtime as *const libc::c_void,
+            mem::size_of::<libc::sock_txtime>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe {
+        libc::clock_gettime(clock, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn pacing_timerfd_fallback() {
+        // a broken timerfd still waits for the deadline
+        let mut pacer = Pacer {
+            pacing: Pacing::TimerFd,
+            timerfd: Some(-1),
+            tai_ref: (Instant::now(), 0),
+        };
+        let deadline = Instant::now() + Duration::from_millis(20);
+        pacer.wait_until(deadline);
+        assert!(Instant::now() >= deadline);
+    }
+}
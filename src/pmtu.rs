@@ -0,0 +1,43 @@
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+use std::io;
+use std::net::UdpSocket;
+
+#[cfg(target_os = "linux")]
+use tos::setsockopt;
+
+/// Set the DF bit on the datagrams sent on `sk`, regardless of the path
+/// MTU the kernel knows, or go back to the default if not `df`.
+///
+/// Datagrams larger than the interface MTU fail to send with `EMSGSIZE`.
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(sk: &UdpSocket, df: bool) -> io::Result<()> {
+    let (v4, v6) = if df {
+        (libc::IP_PMTUDISC_PROBE, libc::IPV6_PMTUDISC_PROBE)
+    } else {
+        (libc::IP_PMTUDISC_WANT, libc::IPV6_PMTUDISC_WANT)
+    };
+    let v4 = setsockopt(sk, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, v4);
+    let v6 = setsockopt(sk, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, v6);
+    v4.or(v6)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_sk: &UdpSocket, _df: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "setting the DF bit needs Linux",
+    ))
+}
+
+/// Whether a send failed because the datagram exceeds the MTU.
+#[cfg(target_os = "linux")]
+pub fn is_too_big(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_too_big(_e: &io::Error) -> bool {
+    false
+}
@@ -0,0 +1,357 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Packet rate of a flow over time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateProfile {
+    Constant(u32),
+    /// linear change from the first to the second rate over the flow
+    Ramp(u32, u32),
+    /// rates that apply from the given offset on, ordered by offset
+    Steps(Vec<(Duration, u32)>),
+    /// `pps` for `on`, then silence for `off`, repeated
+    Burst {
+        pps: u32,
+        on: Duration,
+        off: Duration,
+    },
+}
+
+impl RateProfile {
+    /// Packet rate at offset `t` into a flow of `duration`.
+    pub fn pps_at(&self, t: Duration, duration: Duration) -> u32 {
+        match *self {
+            RateProfile::Constant(pps) => pps,
+            RateProfile::Ramp(from, to) => {
+                let progress = (as_secs(t) / as_secs(duration)).min(1.0);
+                let pps = f64::from(from)
+                    + (f64::from(to) - f64::from(from)) * progress;
+                pps as u32
+            }
+            RateProfile::Steps(ref steps) => steps
+                .iter()
+                .take_while(|step| step.0 <= t)
+                .last()
+                .map_or(0, |step| step.1),
+            RateProfile::Burst { pps, on, off } => {
+                let period = as_ns(on + off);
+                if period == 0 || as_ns(t) % period < as_ns(on) {
+                    pps
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    /// Highest packet rate of the profile.
+    pub fn peak_pps(&self) -> u32 {
+        match *self {
+            RateProfile::Constant(pps) => pps,
+            RateProfile::Ramp(from, to) => from.max(to),
+            RateProfile::Steps(ref steps) => {
+                steps.iter().map(|step| step.1).max().unwrap_or(0)
+            }
+            RateProfile::Burst { pps, .. } => pps,
+        }
+    }
+
+    /// Offset after `t` at which the rate changes next, if it does.
+    fn next_change(&self, t: Duration) -> Option<Duration> {
+        match *self {
+            RateProfile::Steps(ref steps) => {
+                steps.iter().map(|step| step.0).find(|&start| start > t)
+            }
+            RateProfile::Burst { on, off, .. } => {
+                let period = as_ns(on + off);
+                let into = as_ns(t) % period;
+                let next = if into < as_ns(on) { as_ns(on) } else { period };
+                Some(t + Duration::from_nanos(next - into))
+            }
+            _ => None,
+        }
+    }
+
+    /// Read `<seconds> <pps>` lines as steps. `#` starts a comment.
+    pub fn from_file(path: &str) -> Result<RateProfile, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let mut steps = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(secs), Some(pps)) => {
+                    steps.push((parse_secs(secs)?, parse_pps(pps)?))
+                }
+                _ => return Err(format!("invalid profile line: {}", line)),
+            }
+        }
+        RateProfile::steps(steps)
+    }
+
+    fn steps(mut steps: Vec<(Duration, u32)>) -> Result<RateProfile, String> {
+        if steps.is_empty() {
+            return Err("profile without steps".to_string());
+        }
+        steps.sort_by_key(|step| step.0);
+        Ok(RateProfile::Steps(steps))
+    }
+}
+
+/// Parses `<pps>`, `ramp:<pps>:<pps>`, `steps:<secs>=<pps>,...`,
+/// `burst:<pps>:<on ms>:<off ms>` and `file:<path>`.
+impl FromStr for RateProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RateProfile, String> {
+        let fields: Vec<&str> = s.splitn(2, ':').collect();
+        match (fields[0], fields.get(1)) {
+            (pps, None) => Ok(RateProfile::Constant(parse_pps(pps)?)),
+            ("ramp", Some(args)) => {
+                let rates: Vec<&str> = args.split(':').collect();
+                if rates.len() != 2 {
+                    return Err("ramp needs two rates".to_string());
+                }
+                Ok(RateProfile::Ramp(
+                    parse_pps(rates[0])?,
+                    parse_pps(rates[1])?,
+                ))
+            }
+            ("steps", Some(args)) => {
+                let mut steps = vec![];
+                for step in args.split(',') {
+                    let step: Vec<&str> = step.split('=').collect();
+                    if step.len() != 2 {
+                        return Err("steps are <secs>=<pps>".to_string());
+                    }
+                    steps.push((parse_secs(step[0])?, parse_pps(step[1])?));
+                }
+                RateProfile::steps(steps)
+            }
+            ("burst", Some(args)) => {
+                let args: Vec<&str> = args.split(':').collect();
+                if args.len() != 3 {
+                    return Err("burst is <pps>:<on ms>:<off ms>".to_string());
+                }
+                let ms = |s: &str| {
+                    s.parse::<u64>()
+                        .map(Duration::from_millis)
+                        .map_err(|e| e.to_string())
+                };
+                let (on, off) = (ms(args[1])?, ms(args[2])?);
+                if on == Duration::new(0, 0) {
+                    return Err("burst needs an on period".to_string());
+                }
+                Ok(RateProfile::Burst {
+                    pps: parse_pps(args[0])?,
+                    on,
+                    off,
+                })
+            }
+            ("file", Some(path)) => RateProfile::from_file(path),
+            (kind, _) => Err(format!("unknown rate profile: {}", kind)),
+        }
+    }
+}
+
+/// Time slots of the datagrams of a flow that follows a rate profile.
+pub struct Schedule<'a> {
+    profile: &'a RateProfile,
+    started_at: Instant,
+    duration: Duration,
+    /// start and rate of the current stretch of constant rate
+    segment: (Duration, u32),
+    /// slots handed out in the current segment
+    cnt: u64,
+    /// offset of the next slot
+    t: Duration,
+}
+
+impl<'a> Schedule<'a> {
+    pub fn new(
+        profile: &'a RateProfile,
+        started_at: Instant,
+        duration: Duration,
+    ) -> Schedule<'a> {
+        Schedule {
+            profile,
+            started_at,
+            duration,
+            segment: (Duration::new(0, 0), 0),
+            cnt: 0,
+            t: Duration::new(0, 0),
+        }
+    }
+}
+
+impl<'a> Iterator for Schedule<'a> {
+    type Item = Instant;
+
+    fn next(&mut self) -> Option<Instant> {
+        if let RateProfile::Ramp(from, to) = *self.profile {
+            let slot = ramp_slot(from, to, self.duration, self.cnt)?;
+            self.cnt += 1;
+            return if slot >= self.duration {
+                None
+            } else {
+                Some(self.started_at + slot)
+            };
+        }
+
+        loop {
+            if self.t >= self.duration {
+                return None;
+            }
+            let pps = self.profile.pps_at(self.t, self.duration);
+            if pps == 0 {
+                self.t = self.profile.next_change(self.t)?;
+                self.segment = (self.t, 0);
+                continue;
+            }
+            if pps != self.segment.1 {
+                self.segment = (self.t, pps);
+                self.cnt = 0;
+            }
+
+            // offsets are relative to the segment start (as opposed to the
+            // last slot) to not accumulate rounding errors.
+            let slot = self.t;
+            self.cnt += 1;
+            let offset = self.cnt * 1_000_000_000 / u64::from(pps);
+            self.t = self.segment.0 + Duration::from_nanos(offset);
+            return Some(self.started_at + slot);
+        }
+    }
+}
+
+/// Offset of the `cnt`th slot of a ramp, i.e. when the integral of the
+/// rate reaches `cnt`.
+fn ramp_slot(
+    from: u32,
+    to: u32,
+    duration: Duration,
+    cnt: u64,
+) -> Option<Duration> {
+    let (from, cnt) = (f64::from(from), cnt as f64);
+    let slope = (f64::from(to) - from) / as_secs(duration);
+    let secs = if slope == 0.0 {
+        cnt / from
+    } else {
+        let discriminant = from * from + 2.0 * slope * cnt;
+        if discriminant < 0.0 {
+            return None;
+        }
+        (discriminant.sqrt() - from) / slope
+    };
+    if secs.is_finite() && secs >= 0.0 {
+        Some(Duration::from_nanos((secs * 1e9) as u64))
+    } else {
+        None
+    }
+}
+
+fn parse_pps(s: &str) -> Result<u32, String> {
+    s.parse::<u32>()
+        .map_err(|e| format!("invalid rate {}: {}", s, e))
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    let secs = s
+        .parse::<f64>()
+        .map_err(|e| format!("invalid offset {}: {}", s, e))?;
+    if secs < 0.0 {
+        return Err(format!("negative offset: {}", s));
+    }
+    Ok(Duration::from_nanos((secs * 1e9) as u64))
+}
+
+fn as_ns(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+fn as_secs(d: Duration) -> f64 {
+    as_ns(d) as f64 / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slots(profile: &RateProfile, duration: Duration) -> Vec<Duration> {
+        let start = Instant::now();
+        Schedule::new(profile, start, duration)
+            .map(|slot| slot - start)
+            .collect()
+    }
+
+    #[test]
+    fn profile_parse() {
+        assert_eq!("500".parse(), Ok(RateProfile::Constant(500)));
+        assert_eq!("ramp:10:20".parse(), Ok(RateProfile::Ramp(10, 20)));
+        assert_eq!(
+            "steps:1.5=20,0=10".parse(),
+            Ok(RateProfile::Steps(vec![
+                (Duration::from_secs(0), 10),
+                (Duration::from_millis(1500), 20),
+            ]))
+        );
+        assert_eq!(
+            "burst:100:20:80".parse(),
+            Ok(RateProfile::Burst {
+                pps: 100,
+                on: Duration::from_millis(20),
+                off: Duration::from_millis(80),
+            })
+        );
+        assert!("ramp:10".parse::<RateProfile>().is_err());
+        assert!("sawtooth:10".parse::<RateProfile>().is_err());
+    }
+
+    #[test]
+    fn schedule_constant() {
+        let slots = slots(&RateProfile::Constant(3), Duration::from_secs(1));
+        assert_eq!(
+            slots,
+            [
+                Duration::new(0, 0),
+                Duration::from_nanos(333_333_333),
+                Duration::from_nanos(666_666_666),
+            ]
+        );
+    }
+
+    #[test]
+    fn schedule_steps() {
+        let profile = "steps:0=10,0.5=0,0.8=100".parse().unwrap();
+        let slots = slots(&profile, Duration::from_secs(1));
+        // 5 slots up to 0.5 s, none until 0.8 s, then 20
+        assert_eq!(slots.len(), 25);
+        assert_eq!(slots[5], Duration::from_millis(800));
+    }
+
+    #[test]
+    fn schedule_burst() {
+        let profile = "burst:1000:100:400".parse().unwrap();
+        let slots = slots(&profile, Duration::from_secs(1));
+        assert_eq!(slots.len(), 200);
+        assert!(slots
+            .iter()
+            .all(|slot| slot.subsec_nanos() % 500_000_000 <= 100_000_000));
+    }
+
+    #[test]
+    fn schedule_ramp() {
+        let profile = RateProfile::Ramp(0, 2000);
+        let slots = slots(&profile, Duration::from_secs(1));
+        // the mean rate is 1000 pps
+        assert!((990..=1010).contains(&slots.len()), "{}", slots.len());
+        let late = slots.iter().filter(|s| s.subsec_nanos() >= 500_000_000);
+        assert!(late.count() > 700);
+    }
+}
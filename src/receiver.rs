@@ -0,0 +1,163 @@
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+use mmsg;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(target_os = "linux")]
+use tos;
+
+/// Largest UDP datagram, so that no datagram gets cut short.
+const MAX_DATAGRAM: usize = 65535;
+
+/// Length, arrival time, TOS byte and source address of a received
+/// datagram.
+pub type Dgram = (usize, Option<Duration>, Option<u8>, Option<SocketAddr>);
+
+/// Receives datagrams along with their time of arrival, their source and,
+/// on Linux, their TOS byte.
+///
+/// With the `mmsg` feature, datagrams are read in batches with `recvmmsg`
+/// and stamped by the kernel. Otherwise they are read one at a time and
+/// stamped on return from `recv`.
+pub struct Receiver {
+    sk: UdpSocket,
+    bufs: Vec<Box<[u8]>>,
+    dgrams: Vec<Dgram>,
+}
+
+impl Receiver {
+    pub fn from_socket(sk: UdpSocket, batch: usize) -> Receiver {
+        #[cfg(all(target_os = "linux", feature = "mmsg"))]
+        mmsg::enable_timestamps(&sk).unwrap_or_else(|e| {
+            println!("no kernel timestamps: {}", e);
+        });
+        #[cfg(target_os = "linux")]
+        tos::enable_recv_tos(&sk).unwrap_or_else(|e| {
+            println!("no TOS of received datagrams: {}", e);
+        });
+        // without recvmmsg, there is nothing to batch
+        let batch = if cfg!(all(target_os = "linux", feature = "mmsg")) {
+            batch.max(1)
+        } else {
+            1
+        };
+        Receiver {
+            sk,
+            bufs: vec![vec![0; MAX_DATAGRAM].into_boxed_slice(); batch],
+            dgrams: vec![(0, None, None, None); batch],
+        }
+    }
+
+    /// Wait for datagrams and return how many were received.
+    ///
+    /// They can be accessed with `datagram` until the next call.
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    pub fn recv(&mut self) -> io::Result<usize> {
+        let cnt =
+            mmsg::recv_batch(&self.sk, &mut self.bufs, &mut self.dgrams)?;
+        let now = since_epoch();
+        for dgram in self.dgrams.iter_mut().take(cnt) {
+            dgram.1 = dgram.1.or(Some(now));
+        }
+        Ok(cnt)
+    }
+
+    /// Wait for datagrams and return how many were received.
+    ///
+    /// They can be accessed with `datagram` until the next call.
+    #[cfg(all(target_os = "linux", not(feature = "mmsg")))]
+    pub fn recv(&mut self) -> io::Result<usize> {
+        let (len, tos, from) = tos::recv(&self.sk, &mut self.bufs[0])?;
+        self.dgrams[0] = (len, Some(since_epoch()), tos, from);
+        Ok(1)
+    }
+
+    /// Wait for datagrams and return how many were received.
+    ///
+    /// They can be accessed with `datagram` until the next call.
+    #[cfg(not(target_os = "linux"))]
+    pub fn recv(&mut self) -> io::Result<usize> {
+        let (len, from) = self.sk.recv_from(&mut self.bufs[0])?;
+        self.dgrams[0] = (len, Some(since_epoch()), None, Some(from));
+        Ok(1)
+    }
+
+    /// Payload and arrival time (since the unix epoch) of the `idx`th
+    /// datagram of the last `recv`.
+    pub fn datagram(&self, idx: usize) -> (&[u8], Duration) {
+        let (len, ts, _, _) = self.dgrams[idx];
+        (&self.bufs[idx][..len], ts.unwrap_or_default())
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.sk
+    }
+
+    /// Source address of the `idx`th datagram of the last `recv`.
+    pub fn source(&self, idx: usize) -> Option<SocketAddr> {
+        self.dgrams[idx].3
+    }
+
+    /// TOS byte of the `idx`th datagram of the last `recv`, if known.
+    pub fn tos(&self, idx: usize) -> Option<u8> {
+        self.dgrams[idx].2
+    }
+}
+
+/// Time since the unix epoch, as datagrams are stamped.
+pub fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::fresh_pair_of_socks;
+
+    #[test]
+    fn receiver_timestamps() {
+        let (sk, sk_rcv) = fresh_pair_of_socks();
+        let mut receiver = Receiver::from_socket(sk_rcv, 8);
+
+        let before = since_epoch();
+        for len in 1..4 {
+            sk.send(&vec![0; len]).expect("send datagram");
+        }
+
+        let mut lens = vec![];
+        while lens.len() < 3 {
+            let cnt = receiver.recv().expect("receive datagrams");
+            for idx in 0..cnt {
+                let (data, ts) = receiver.datagram(idx);
+                assert!(ts >= before - Duration::from_millis(1));
+                assert!(ts <= since_epoch());
+                assert_eq!(receiver.source(idx), sk.local_addr().ok());
+                lens.push(data.len());
+            }
+        }
+        assert_eq!(lens, [1, 2, 3]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn receiver_tos() {
+        let (sk, sk_rcv) = fresh_pair_of_socks();
+        let mut receiver = Receiver::from_socket(sk_rcv, 8);
+
+        tos::set_tos(&sk, 0xb8).expect("set TOS");
+        sk.send(&[0; 10]).expect("send datagram");
+        assert_eq!(receiver.recv().expect("receive datagram"), 1);
+        assert_eq!(receiver.tos(0), Some(0xb8));
+
+        // sessions send from dual-stack sockets
+        let sk6 = UdpSocket::bind("[::]:0").expect("bind sender");
+        sk6.connect(sk.peer_addr().expect("get receiver address"))
+            .expect("connect to receiver");
+        tos::set_tos(&sk6, 0x20).expect("set TOS");
+        sk6.send(&[0; 10]).expect("send datagram");
+        assert_eq!(receiver.recv().expect("receive datagram"), 1);
+        assert_eq!(receiver.tos(0), Some(0x20));
+    }
+}
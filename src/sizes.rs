@@ -0,0 +1,228 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+
+/// Smallest payload that holds the sequence header of a flow with all
+/// optional fields set.
+pub const MIN_LEN: usize = 100;
+/// Largest UDP payload over IPv4.
+pub const MAX_LEN: usize = 65507;
+
+/// Simple IMIX: 7:4:1 of 40, 576 and 1500 byte IPv4 packets, as UDP
+/// payload. The smallest size is raised to `MIN_LEN`.
+const IMIX: [(usize, u32); 3] = [(MIN_LEN, 7), (548, 4), (1472, 1)];
+/// Sizes a uniform range is divided into.
+const UNIFORM_CLASSES: usize = 16;
+
+/// Distribution of the payload lengths of a flow.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketSizes {
+    Fixed(usize),
+    Imix,
+    /// evenly spaced sizes from the first to the second length
+    Uniform(usize, usize),
+    /// lengths and their weights
+    Histogram(Vec<(usize, u32)>),
+}
+
+impl PacketSizes {
+    /// Size classes of the distribution along with their weights.
+    pub fn classes(&self) -> Vec<(usize, u32)> {
+        match *self {
+            PacketSizes::Fixed(len) => vec![(len, 1)],
+            PacketSizes::Imix => IMIX.to_vec(),
+            PacketSizes::Uniform(min, max) => {
+                let steps = UNIFORM_CLASSES - 1;
+                let mut classes: Vec<(usize, u32)> = (0..=steps)
+                    .map(|i| (min + (max - min) * i / steps, 1))
+                    .collect();
+                classes.dedup_by_key(|class| class.0);
+                classes
+            }
+            PacketSizes::Histogram(ref classes) => classes.clone(),
+        }
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.classes()
+            .iter()
+            .map(|class| class.0)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether all datagrams have the same size.
+    pub fn is_fixed(&self) -> bool {
+        self.classes().len() == 1
+    }
+
+    /// Endless sequence of lengths that follows the distribution.
+    pub fn iter(&self) -> Sizes {
+        let classes = self.classes();
+        Sizes {
+            current: vec![0; classes.len()],
+            total: classes.iter().map(|class| i64::from(class.1)).sum(),
+            classes,
+        }
+    }
+
+    /// Read `<length> <weight>` lines as a histogram. `#` starts a
+    /// comment.
+    pub fn from_file(path: &str) -> Result<PacketSizes, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let mut classes = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(len), Some(weight)) => {
+                    classes.push((parse_len(len)?, parse_weight(weight)?))
+                }
+                _ => return Err(format!("invalid histogram line: {}", line)),
+            }
+        }
+        PacketSizes::histogram(classes)
+    }
+
+    fn histogram(
+        mut classes: Vec<(usize, u32)>,
+    ) -> Result<PacketSizes, String> {
+        classes.retain(|class| class.1 > 0);
+        if classes.is_empty() {
+            return Err("histogram without weights".to_string());
+        }
+        classes.sort_by_key(|class| class.0);
+        Ok(PacketSizes::Histogram(classes))
+    }
+}
+
+/// Parses `<len>`, `imix`, `uniform:<len>:<len>`, `hist:<len>=<weight>,...`
+/// and `file:<path>`.
+impl FromStr for PacketSizes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PacketSizes, String> {
+        let fields: Vec<&str> = s.splitn(2, ':').collect();
+        match (fields[0], fields.get(1)) {
+            ("imix", None) => Ok(PacketSizes::Imix),
+            (len, None) => Ok(PacketSizes::Fixed(parse_len(len)?)),
+            ("uniform", Some(args)) => {
+                let lens: Vec<&str> = args.split(':').collect();
+                if lens.len() != 2 {
+                    return Err("uniform needs two lengths".to_string());
+                }
+                let (min, max) = (parse_len(lens[0])?, parse_len(lens[1])?);
+                if min > max {
+                    return Err("uniform needs ascending lengths".to_string());
+                }
+                Ok(PacketSizes::Uniform(min, max))
+            }
+            ("hist", Some(args)) => {
+                let mut classes = vec![];
+                for class in args.split(',') {
+                    let class: Vec<&str> = class.split('=').collect();
+                    if class.len() != 2 {
+                        return Err("classes are <len>=<weight>".to_string());
+                    }
+                    classes.push((
+                        parse_len(class[0])?,
+                        parse_weight(class[1])?,
+                    ));
+                }
+                PacketSizes::histogram(classes)
+            }
+            ("file", Some(path)) => PacketSizes::from_file(path),
+            (kind, _) => Err(format!("unknown packet sizes: {}", kind)),
+        }
+    }
+}
+
+/// Lengths drawn from a `PacketSizes` distribution.
+///
+/// Classes are interleaved by smooth weighted round robin, so any stretch
+/// of the flow follows the distribution, without a random generator.
+pub struct Sizes {
+    classes: Vec<(usize, u32)>,
+    current: Vec<i64>,
+    total: i64,
+}
+
+impl Iterator for Sizes {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        for (current, class) in self.current.iter_mut().zip(&self.classes) {
+            *current += i64::from(class.1);
+        }
+        // the first class wins a tie
+        let best = (0..self.classes.len())
+            .rev()
+            .max_by_key(|&i| self.current[i])?;
+        self.current[best] -= self.total;
+        Some(self.classes[best].0)
+    }
+}
+
+fn parse_len(s: &str) -> Result<usize, String> {
+    let len = s
+        .parse::<usize>()
+        .map_err(|e| format!("invalid length {}: {}", s, e))?;
+    if !(MIN_LEN..=MAX_LEN).contains(&len) {
+        return Err(format!(
+            "length {} is not within {} to {}",
+            len, MIN_LEN, MAX_LEN
+        ));
+    }
+    Ok(len)
+}
+
+fn parse_weight(s: &str) -> Result<u32, String> {
+    s.parse::<u32>()
+        .map_err(|e| format!("invalid weight {}: {}", s, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_parse() {
+        assert_eq!("1200".parse(), Ok(PacketSizes::Fixed(1200)));
+        assert_eq!("imix".parse(), Ok(PacketSizes::Imix));
+        assert_eq!(
+            "uniform:100:200".parse(),
+            Ok(PacketSizes::Uniform(100, 200))
+        );
+        assert_eq!(
+            "hist:1000=1,100=3".parse(),
+            Ok(PacketSizes::Histogram(vec![(100, 3), (1000, 1)]))
+        );
+        assert!("10".parse::<PacketSizes>().is_err());
+        assert!("uniform:200:100".parse::<PacketSizes>().is_err());
+        assert!("hist:100=0".parse::<PacketSizes>().is_err());
+        assert!("trimodal".parse::<PacketSizes>().is_err());
+    }
+
+    #[test]
+    fn sizes_uniform_classes() {
+        let classes = PacketSizes::Uniform(100, 250).classes();
+        assert_eq!(classes.len(), UNIFORM_CLASSES);
+        assert_eq!(classes[0], (100, 1));
+        assert_eq!(classes[1], (110, 1));
+        assert_eq!(classes[UNIFORM_CLASSES - 1], (250, 1));
+        assert_eq!(PacketSizes::Uniform(100, 102).classes().len(), 3);
+    }
+
+    #[test]
+    fn sizes_imix_mix() {
+        let lens: Vec<usize> = PacketSizes::Imix.iter().take(24).collect();
+        let cnt = |len| lens.iter().filter(|&&l| l == len).count();
+        assert_eq!((cnt(MIN_LEN), cnt(548), cnt(1472)), (14, 8, 2));
+        // the large datagrams are spread over the flow
+        assert_eq!(lens[..12].iter().filter(|&&l| l == 1472).count(), 1);
+    }
+}
@@ -0,0 +1,37 @@
+extern crate libc;
+
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+/// Room for the source address of a received datagram.
+pub fn storage() -> libc::sockaddr_storage {
+    unsafe { mem::zeroed() }
+}
+
+/// Source address the kernel filled into `storage`.
+pub fn to_std(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match libc::c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let sin = unsafe {
+                &*(storage as *const _ as *const libc::sockaddr_in)
+            };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            let port = u16::from_be(sin.sin_port);
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe {
+                &*(storage as *const _ as *const libc::sockaddr_in6)
+            };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            let port = u16::from_be(sin6.sin6_port);
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                port,
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
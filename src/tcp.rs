@@ -0,0 +1,193 @@
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+use analyze::arrival::as_ns;
+use bitrate::Bitrate;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+/// Bytes handed to the kernel at once.
+const CHUNK: usize = 64 * 1024;
+/// Interval of the samples of the sender's TCP state.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the receiver of a bulk transfer saw.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TcpReport {
+    pub bytes: u64,
+    /// seconds from the first to the last byte
+    pub secs: f64,
+}
+
+impl TcpReport {
+    /// Rate of the received bytes.
+    pub fn goodput(&self) -> Bitrate {
+        if self.secs > 0.0 {
+            Bitrate((self.bytes as f64 * 8.0 / self.secs) as u64)
+        } else {
+            Bitrate(0)
+        }
+    }
+}
+
+/// The sender's view of the connection, from `TCP_INFO`.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpSample {
+    pub rtt: Duration,
+    /// congestion window in segments
+    pub cwnd: u32,
+    /// segment size
+    pub mss: u32,
+    /// segments retransmitted so far
+    pub retrans: u32,
+}
+
+/// Statistics of the sender of a bulk transfer.
+#[derive(Debug, Default)]
+pub struct TcpStats {
+    /// bytes handed to the kernel
+    pub bytes: u64,
+    pub samples: Vec<TcpSample>,
+}
+
+impl TcpStats {
+    /// Smallest, mean and largest round trip time of the samples.
+    pub fn rtt(&self) -> Option<(Duration, Duration, Duration)> {
+        let rtts = self.samples.iter().map(|s| s.rtt);
+        let min = rtts.clone().min()?;
+        let max = rtts.clone().max()?;
+        let mean = rtts.sum::<Duration>() / self.samples.len() as u32;
+        Some((min, mean, max))
+    }
+
+    /// Largest congestion window of the samples in bytes.
+    pub fn max_cwnd(&self) -> u32 {
+        self.samples
+            .iter()
+            .map(|s| s.cwnd * s.mss)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn retransmits(&self) -> u32 {
+        self.samples.last().map_or(0, |s| s.retrans)
+    }
+}
+
+/// Sample the sender's state of the connection.
+#[cfg(target_os = "linux")]
+pub fn sample(sk: &TcpStream) -> io::Result<TcpSample> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            sk.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(TcpSample {
+        rtt: Duration::from_micros(u64::from(info.tcpi_rtt)),
+        cwnd: info.tcpi_snd_cwnd,
+        mss: info.tcpi_snd_mss,
+        retrans: info.tcpi_total_retrans,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sample(_sk: &TcpStream) -> io::Result<TcpSample> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "sampling TCP_INFO needs Linux",
+    ))
+}
+
+/// Send as much as `sk` takes for `duration`, then close the sending
+/// side. The sender's state is sampled along the way where supported.
+pub fn send_bulk(
+    sk: &mut TcpStream,
+    duration: Duration,
+) -> io::Result<TcpStats> {
+    let buf = vec![0; CHUNK];
+    let mut stats = TcpStats::default();
+    // a blocked write must not hold up the samples
+    sk.set_write_timeout(Some(SAMPLE_INTERVAL))?;
+
+    let start = Instant::now();
+    let mut next_sample = start;
+    while start.elapsed() < duration {
+        match sk.write(&buf) {
+            Ok(len) => stats.bytes += len as u64,
+            Err(ref e) if is_timeout(e) => (),
+            Err(e) => return Err(e),
+        }
+        if Instant::now() >= next_sample {
+            if let Ok(sample) = sample(sk) {
+                stats.samples.push(sample);
+            }
+            next_sample += SAMPLE_INTERVAL;
+        }
+    }
+    sk.set_write_timeout(None)?;
+    sk.shutdown(Shutdown::Write)?;
+    Ok(stats)
+}
+
+/// Receive a bulk transfer on `sk` until the sender closes its side.
+pub fn receive_bulk(sk: &mut TcpStream) -> io::Result<TcpReport> {
+    let mut buf = vec![0; CHUNK];
+    let mut report = TcpReport::default();
+    let mut first = None;
+    loop {
+        let len = sk.read(&mut buf)?;
+        if len == 0 {
+            return Ok(report);
+        }
+        let first = *first.get_or_insert_with(Instant::now);
+        report.bytes += len as u64;
+        report.secs = as_ns(first.elapsed()) as f64 / 1e9;
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+        || e.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn tcp_bulk() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let (mut sk, _) = listener.accept().expect("accept");
+            receive_bulk(&mut sk)
+        });
+
+        let mut sk = TcpStream::connect(addr).expect("connect");
+        let stats = send_bulk(&mut sk, Duration::from_millis(300))
+            .expect("send bulk");
+        let report = receiver.join().unwrap().expect("receive bulk");
+
+        assert_eq!(report.bytes, stats.bytes);
+        assert!(report.goodput().0 > 0);
+        if cfg!(target_os = "linux") {
+            assert!(stats.samples.len() >= 2);
+            assert!(stats.max_cwnd() > 0);
+        }
+    }
+}
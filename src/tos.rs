@@ -0,0 +1,267 @@
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+use std::fmt;
+use std::io;
+use std::net::UdpSocket;
+use std::str::FromStr;
+
+#[cfg(all(target_os = "linux", not(feature = "mmsg")))]
+use sockaddr;
+#[cfg(target_os = "linux")]
+use std::mem;
+#[cfg(all(target_os = "linux", not(feature = "mmsg")))]
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+/// A DiffServ code point, the upper six bits of the TOS byte.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Dscp(pub u8);
+
+impl Dscp {
+    pub fn from_tos(tos: u8) -> Dscp {
+        Dscp(tos >> 2)
+    }
+
+    /// TOS byte with the ECN bits cleared.
+    pub fn tos(self) -> u8 {
+        self.0 << 2
+    }
+}
+
+/// Parses a code point as number or as `be`, `ef`, `cs<n>` or `af<nm>`.
+impl FromStr for Dscp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dscp, String> {
+        let invalid = || format!("invalid DSCP: {}", s);
+        let name = s.to_lowercase();
+        let digit = |i: usize| match name.as_bytes()[i] {
+            d @ b'0'..=b'9' => Ok(d - b'0'),
+            _ => Err(invalid()),
+        };
+        let dscp = match name.as_str() {
+            "be" | "df" => 0,
+            "ef" => 46,
+            "va" => 44,
+            name if name.starts_with("cs") && name.len() == 3 => {
+                match digit(2)? {
+                    class @ 0..=7 => class << 3,
+                    _ => return Err(invalid()),
+                }
+            }
+            name if name.starts_with("af") && name.len() == 4 => {
+                match (digit(2)?, digit(3)?) {
+                    (class @ 1..=4, drop @ 1..=3) => class << 3 | drop << 1,
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => s.parse::<u8>().map_err(|_| invalid())?,
+        };
+        if dscp > 63 {
+            return Err(invalid());
+        }
+        Ok(Dscp(dscp))
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            0 => write!(f, "be"),
+            46 => write!(f, "ef"),
+            44 => write!(f, "va"),
+            d if d & 7 == 0 => write!(f, "cs{}", d >> 3),
+            d if d & 1 == 0
+                && (1..=4).contains(&(d >> 3))
+                && (1..=3).contains(&(d >> 1 & 3)) =>
+            {
+                write!(f, "af{}{}", d >> 3, d >> 1 & 3)
+            }
+            d => write!(f, "{}", d),
+        }
+    }
+}
+
+/// ECN codepoint, the lower two bits of the TOS byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    /// congestion experienced
+    Ce,
+}
+
+impl Ecn {
+    pub fn from_tos(tos: u8) -> Ecn {
+        match tos & 3 {
+            0 => Ecn::NotEct,
+            1 => Ecn::Ect1,
+            2 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+
+    /// TOS byte with the DSCP bits cleared.
+    pub fn tos(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0,
+            Ecn::Ect1 => 1,
+            Ecn::Ect0 => 2,
+            Ecn::Ce => 3,
+        }
+    }
+}
+
+/// Parses the codepoints a sender may use: `off`, `ect0` and `ect1`.
+impl FromStr for Ecn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Ecn, String> {
+        match s {
+            "off" => Ok(Ecn::NotEct),
+            "ect0" => Ok(Ecn::Ect0),
+            "ect1" => Ok(Ecn::Ect1),
+            _ => Err(format!("unsupported ECN codepoint: {}", s)),
+        }
+    }
+}
+
+/// Mark the datagrams sent on `sk` with `tos`.
+///
+/// Both the IPv4 and the IPv6 option are set, so that IPv4-mapped
+/// destinations of IPv6 sockets get marked, too.
+#[cfg(target_os = "linux")]
+pub fn set_tos(sk: &UdpSocket, tos: u8) -> io::Result<()> {
+    let v4 = setsockopt(sk, libc::IPPROTO_IP, libc::IP_TOS, tos.into());
+    let v6 =
+        setsockopt(sk, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos.into());
+    v4.or(v6)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tos(_sk: &UdpSocket, tos: u8) -> io::Result<()> {
+    if tos == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "TOS marking needs Linux",
+        ))
+    }
+}
+
+/// Have the kernel pass the TOS byte of incoming datagrams along.
+#[cfg(target_os = "linux")]
+pub fn enable_recv_tos(sk: &UdpSocket) -> io::Result<()> {
+    let v4 = setsockopt(sk, libc::IPPROTO_IP, libc::IP_RECVTOS, 1);
+    let v6 = setsockopt(sk, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1);
+    v4.or(v6)
+}
+
+#[cfg(target_os = "linux")]
+pub fn setsockopt(
+    sk: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            sk.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// TOS byte of a received datagram, if the kernel passed it along.
+#[cfg(target_os = "linux")]
+pub fn rx_tos(msg: &libc::msghdr) -> Option<u8> {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_TOS) => return Some(*data),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    let tclass =
+                        (data as *const libc::c_int).read_unaligned();
+                    return Some(tclass as u8);
+                }
+                _ => (),
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    None
+}
+
+/// Receive a datagram along with its TOS byte and its source.
+#[cfg(all(target_os = "linux", not(feature = "mmsg")))]
+pub fn recv(
+    sk: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, Option<u8>, Option<SocketAddr>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 8];
+    let mut name = sockaddr::storage();
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&name) as _;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let ret = unsafe { libc::recvmsg(sk.as_raw_fd(), &mut msg, 0) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok((ret as usize, rx_tos(&msg), sockaddr::to_std(&name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dscp_parse() {
+        assert_eq!("ef".parse(), Ok(Dscp(46)));
+        assert_eq!("CS1".parse(), Ok(Dscp(8)));
+        assert_eq!("af41".parse(), Ok(Dscp(34)));
+        assert_eq!("10".parse(), Ok(Dscp(10)));
+        assert!("af51".parse::<Dscp>().is_err());
+        assert!("64".parse::<Dscp>().is_err());
+        assert!("cs".parse::<Dscp>().is_err());
+        for name in ["be", "ef", "va", "cs6", "af11", "af43", "13"].iter() {
+            let dscp: Dscp = name.parse().unwrap();
+            assert_eq!(&dscp.to_string(), name);
+        }
+        assert_eq!(Dscp(46).tos(), 0xb8);
+        assert_eq!(Dscp::from_tos(0xbb), Dscp(46));
+    }
+
+    #[test]
+    fn ecn_parse() {
+        assert_eq!("ect0".parse(), Ok(Ecn::Ect0));
+        assert_eq!("off".parse(), Ok(Ecn::NotEct));
+        assert!("ce".parse::<Ecn>().is_err());
+        assert_eq!(Dscp(46).tos() | Ecn::Ect1.tos(), 0xb9);
+        assert_eq!(Ecn::from_tos(0xbb), Ecn::Ce);
+    }
+}